@@ -0,0 +1,51 @@
+//! Benchmarks comparing the `Matcher` search strategies.
+//!
+//! Run with `cargo bench`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use looneygrep::Matcher;
+
+/// Builds a log-like haystack where one line in a hundred contains the needle.
+fn sample_lines() -> Vec<String> {
+    (0..10_000)
+        .map(|i| {
+            let tail = if i % 100 == 0 { "needle" } else { "haystack" };
+            format!("{} INFO request user=alpha session=beta timeout=30s status=ok {}", i, tail)
+        })
+        .collect()
+}
+
+fn bench_is_match(c: &mut Criterion) {
+    let lines = sample_lines();
+    let mut group = c.benchmark_group("is_match");
+    for ignore_case in [false, true] {
+        let matchers = [
+            ("substring", Matcher::substring("needle", ignore_case)),
+            ("memmem", Matcher::new("needle", ignore_case)),
+        ];
+        for (name, matcher) in matchers {
+            let id = if ignore_case { format!("{}/ignore-case", name) } else { name.to_string() };
+            group.bench_function(id, |b| {
+                b.iter(|| lines.iter().filter(|line| matcher.is_match(black_box(line))).count())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_find_all(c: &mut Criterion) {
+    let line = "needle ".repeat(200);
+    let mut group = c.benchmark_group("find_all");
+    for (name, matcher) in [
+        ("substring", Matcher::substring("needle", false)),
+        ("memmem", Matcher::new("needle", false)),
+    ] {
+        group.bench_function(name, |b| b.iter(|| matcher.find_all(black_box(&line)).len()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_is_match, bench_find_all);
+criterion_main!(benches);
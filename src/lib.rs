@@ -6,13 +6,18 @@
 //!
 //! ## Example
 //!
-//! ```rust
+//! ```rust,no_run
 //! use looneygrep::{Config, run};
 //! # fn main() -> Result<(), Box<dyn std::error::Error>> {
-//! let config = Config { 
-//!     query: String::from("foo"), 
+//! let config = Config {
+//!     query: String::from("foo"),
 //!     file_path: String::from("bar.txt"),
-//!     ignore_case: false
+//!     ignore_case: false,
+//!     replace: false,
+//!     url: None,
+//!     context: 0,
+//!     search_all: false,
+//!     ..Default::default()
 //! };
 //! run(config)?;
 //! # Ok(())
@@ -36,11 +41,28 @@
 
 use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::io::{self, Write};
-use syntect::easy::HighlightLines;
+use std::collections::HashSet;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+use memchr::memmem::Finder;
+use memmap2::Mmap;
+use regex::{Regex, RegexBuilder};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, LOCATION};
+use reqwest::redirect::Policy;
+use serde::{Deserialize, Serialize};
+use syntect::easy::{HighlightLines, ScopeRegionIterator};
 use syntect::highlighting::{ThemeSet, Style};
-use syntect::parsing::SyntaxSet;
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
 use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
 
 /// Configuration for the search operation.
@@ -48,13 +70,16 @@ use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
 /// This struct holds all options for a search, including the query string,
 /// file path, case sensitivity, replacement mode, URL, context lines, and
 /// whether to search all files in the current directory.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 /// Configuration for the search operation.
 pub struct Config {
     /// The string to search for.
     pub query: String,
     /// The path to the file to search.
     pub file_path: String,
+    /// All paths given on the command line, in order. `-` means standard input.
+    /// If empty, `file_path` is searched instead.
+    pub paths: Vec<String>,
     /// Whether the search is case-insensitive.
     pub ignore_case: bool,
     /// Whether to prompt for replacement of matches.
@@ -65,327 +90,3605 @@ pub struct Config {
     pub context: usize,
     /// If true, search all files in the current directory.
     pub search_all: bool,
+    /// If true, note `(top of file)` / `(end of file)` where the context window is clipped.
+    pub mark_file_edges: bool,
+    /// Line printed between match groups. `---` is used when `None`.
+    pub context_separator: Option<String>,
+    /// If true, nothing is printed between match groups.
+    pub no_context_separator: bool,
+    /// If set, only lines in this 1-based, inclusive range can match.
+    pub line_range: Option<(usize, usize)>,
+    /// If set, only the first this many lines of each file can match.
+    pub head: Option<usize>,
+    /// If set, only the last this many lines of each file can match.
+    pub tail: Option<usize>,
+    /// If true, print only the number of matching lines instead of the matches.
+    pub count: bool,
+    /// If true, `count` also shows the number of lines searched and the share
+    /// that matched, as `42/1000 (4.2%)`.
+    pub percent: bool,
+    /// If true, print only the names of sources that contain a match.
+    pub files_with_matches: bool,
+    /// If true, print only the names of sources that contain no match.
+    pub files_without_match: bool,
+    /// If true, the query is matched against file paths instead of file contents.
+    pub name_only: bool,
+    /// If true, names printed by `files_with_matches` or `files_without_match`
+    /// end with a NUL byte instead of a newline.
+    pub print0: bool,
+    /// If true, each printed line (each match, in multiline mode) ends with a NUL
+    /// byte instead of a newline, and the preview heading and separators are left out.
+    pub null: bool,
+    /// If true, print a grand total of matching lines after searching with `--all`.
+    pub total: bool,
+    /// If true, print a table of match counts per file, highest first, after searching.
+    pub summary: bool,
+    /// If true, files are shown by their absolute path.
+    pub absolute_path: bool,
+    /// If true, no per-file headers are printed, however many sources are searched.
+    pub no_filename: bool,
+    /// If true, `--all` also searches files in subdirectories.
+    pub recursive: bool,
+    /// If true, recursive search descends into symlinked directories.
+    pub follow_symlinks: bool,
+    /// Maximum directory depth for recursive search, where 1 is the starting directory only.
+    pub max_depth: Option<usize>,
+    /// If true, show a progress line on stderr while searching with `--all`.
+    pub progress: bool,
+    /// If true, files and URLs that can't be read are skipped without a message on stderr.
+    pub no_messages: bool,
+    /// If true, print search statistics (sources, lines scanned, matches, time) to stderr at the end.
+    pub stats: bool,
+    /// ANSI escape used to highlight matches. Red is used when `None`.
+    pub match_color: Option<String>,
+    /// Whether matches are highlighted with the match color as foreground,
+    /// as background, or in reverse video.
+    pub highlight_style: HighlightStyle,
+    /// If true, a line only matches when it equals the query entirely.
+    pub line_regexp: bool,
+    /// If true, a line only matches when it begins with the query.
+    pub starts_with: bool,
+    /// If true, a line only matches when it ends with the query.
+    pub ends_with: bool,
+    /// If true, trailing whitespace is stripped from printed lines (display only).
+    pub trim: bool,
+    /// Text that replaces each match. `<REPLACED>` is used when `None`.
+    pub replace_with: Option<String>,
+    /// If true, replace every match without prompting.
+    pub yes: bool,
+    /// If true, each match is opened with its context in `$VISUAL`/`$EDITOR` and
+    /// replaced with whatever is saved.
+    pub replace_interactive: bool,
+    /// If true, print every line of each source, with matches highlighted in place.
+    pub highlight_only: bool,
+    /// If true, matching lines are printed with the most occurrences of the
+    /// query first, instead of in file order.
+    pub sort_matches: bool,
+    /// If true, `--replace` prints a unified diff of the changes instead of writing them.
+    pub diff: bool,
+    /// If true, the original of each file is kept as a backup before replacements are written.
+    pub backup: bool,
+    /// If true, `--replace` over directories also rewrites files that look binary or generated.
+    pub force: bool,
+    /// If set, backups go into this directory, mirroring each file's path, instead of `<file>.bak`.
+    pub backup_dir: Option<String>,
+    /// If set, every saved replacement is appended to this file with a timestamp,
+    /// the file and line, and the old and new text.
+    pub replace_log: Option<String>,
+    /// If true, every saved replacement is also printed as a `path:line:old:new`
+    /// record, for scripts to act on.
+    pub replace_report: bool,
+    /// If true, list how many matches and which lines will be replaced before prompting.
+    pub replace_overview: bool,
+    /// If true, a matching line is replaced as a whole rather than just its matches.
+    pub replace_whole_line: bool,
+    /// If set, each match is replaced by itself converted to this case, instead of `replace_with`.
+    pub replace_case: Option<ReplaceCase>,
+    /// If true, each replacement takes the case of the text it replaces: all
+    /// lower, all upper, or capitalized.
+    pub preserve_case: bool,
+    /// If set, at most this many matches are replaced; the rest are left as they are.
+    pub replace_count: Option<usize>,
+    /// If set, this text is inserted before each match instead of replacing it.
+    pub insert_before: Option<String>,
+    /// If set, this text is inserted after each match instead of replacing it.
+    pub insert_after: Option<String>,
+    /// If set, a match is only replaced when this text comes right before it.
+    pub replace_if_preceded_by: Option<String>,
+    /// If set, a match is only replaced when this text comes right after it.
+    pub replace_if_followed_by: Option<String>,
+    /// If set, a file listing more paths to search, one per line (`#` starts a comment line).
+    pub files_from: Option<String>,
+    /// Directory names (or `*`/`?` glob patterns) skipped entirely when searching directories.
+    pub exclude_dirs: Vec<String>,
+    /// If true, memory-map files instead of reading them when not replacing.
+    pub mmap: bool,
+    /// Encoding of the files being searched. UTF-8 is assumed when `None`.
+    pub encoding: Option<&'static Encoding>,
+    /// If set, replaced content is written here instead of overwriting the searched file.
+    pub output: Option<String>,
+    /// If set, printed lines longer than this many characters are truncated.
+    pub max_columns: Option<usize>,
+    /// If set, tabs in printed lines are expanded to the next multiple of this
+    /// many columns. Files are never changed.
+    pub tab_width: Option<usize>,
+    /// At most this many matches are highlighted on one printed line; the rest
+    /// of the line is shown plain; `--highlight-first` sets 1. 500 applies when `None`.
+    pub max_matches_per_line: Option<usize>,
+    /// If true, each printed match is annotated with the nearest function or
+    /// other definition enclosing it.
+    pub show_function: bool,
+    /// Syntax highlighting overrides from `--map-ext`, as `(extension, used extension)` pairs.
+    pub ext_map: Vec<(String, String)>,
+    /// Extra HTTP headers sent with `--url` requests, as `(name, value)` pairs.
+    pub headers: Vec<(String, String)>,
+    /// Maximum number of redirects `--url` requests follow; `Some(0)` follows none.
+    /// The HTTP client's default limit applies when `None`.
+    pub max_redirects: Option<usize>,
+    /// How many times a failed `--url` request is retried, waiting twice as
+    /// long before each retry.
+    pub retries: u32,
+    /// If set, each file is run through this command and its stdout is searched instead.
+    pub pre: Option<String>,
+    /// If set, search results are written to this file, without colors, instead of stdout.
+    pub output_file: Option<String>,
+    /// If true, only matches that form whole words (not next to a letter, digit
+    /// or `_`) count, both when searching and replacing.
+    pub word_regexp: bool,
+    /// If true, the query is a regular expression and `replace_with` may refer to
+    /// its capture groups as `$1`, `$name`, etc.
+    pub regex: bool,
+    /// If true, the regex is matched against each file as a whole, so matches
+    /// can span lines. Requires `regex`.
+    pub multiline: bool,
+    /// If true, `.` in the regex also matches newlines, so `start.*?end` can
+    /// span lines. Requires `multiline`.
+    pub dotall: bool,
+    /// How search results are printed.
+    pub output_format: OutputFormat,
+    /// If true, `--help` was given: print [`USAGE`] instead of searching.
+    pub help: bool,
+    /// If true, `--version` was given: print [`VERSION`] instead of searching.
+    pub version: bool,
+    /// When to use colors in the output.
+    pub color: ColorChoice,
+}
+
+/// The version of looneygrep, printed by `--version`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The usage summary printed by `--help`. See the README for details on each option.
+pub const USAGE: &str = "\
+Usage: looneygrep <query> [<path>...] [options]
+
+Each path may be a file, a directory, `-` for standard input, or a glob.
+
+Matching:
+  --ignore-case                  Case-insensitive search
+  -w, --word-regexp              Only match whole words
+  --no-ignore-case, --no-word-regexp, --no-regex
+                                 Turn off an option set in .looneygreprc
+  -x, --line-regexp              Only match lines equal to the query
+  --starts-with, --ends-with     Only match lines beginning or ending with the query
+  --regex                        Treat the query as a regular expression
+  --multiline                    Let the regex span lines (implies --regex)
+  --dotall                       Let . match newlines (implies --multiline)
+  --range START:END              Only match lines START to END
+  --head <N>                     Only match the first N lines of each file
+  --tail <N>                     Only match the last N lines of each file
+
+Replacing:
+  --replace                      Prompt to replace each match
+  --replace-with <text>          Replacement text (`&` is the matched text)
+  --replace-regex 's/PAT/REP/'   Replace regex PAT with REP
+  --replace-case <case>          Replace matches with themselves in upper, lower or title case
+  --preserve-case                Give each replacement the case of what it replaces
+  --replace-whole-line           Replace each matching line entirely
+  --insert-before <text>         Insert text before each match instead of replacing it
+  --insert-after <text>          Insert text after each match instead of replacing it
+  --replace-interactive          Edit each match in $VISUAL/$EDITOR
+  --replace-if-preceded-by <t>   Only replace matches right after <t>
+  --replace-if-followed-by <t>   Only replace matches right before <t>
+  --replace-count <N>            Replace at most N matches
+  --replace-overview             Print the matches found before replacing
+  --replace-log <file>           Append each saved replacement to <file>
+  --replace-report               Print each saved replacement as path:line:old:new
+  -y, --yes                      Replace every match without prompting
+  --diff                         Print the changes as a unified diff instead
+  --output <path>                Write the result to <path> instead
+  --backup, --backup-dir <dir>   Keep the original of each changed file
+  --force                        Also replace in files that look binary or generated
+
+Sources:
+  --all                          Search all files in the current directory
+  --recursive                    Like --all, but also search subdirectories
+  --max-depth <N>                Descend at most N directory levels
+  --exclude-dir <name>           Skip directories with this name or glob
+  --follow-symlinks              Descend into symlinked directories
+  --files-from <file>            Also search the paths listed in <file>
+  --url <url>                    Search a web page
+  --header \"Name: Value\"         Send an extra HTTP header with --url
+  --no-redirect                  Don't follow HTTP redirects
+  --max-redirects <N>            Follow at most N HTTP redirects
+  --retries <N>                  Retry failed URL requests N times
+  --pre <command>                Search the output of <command> <file>
+  --encoding <label>             Decode files from this encoding
+  --mmap                         Memory-map files instead of reading them
+
+Output:
+  --context <N>                  Show N lines of context around each match
+  --context-separator <text>     Line printed between match groups
+  --no-context-separator         Print nothing between match groups
+  --mark-file-edges              Mark context cut short by the file's start or end
+  --count                        Print only the number of matching lines
+  --percent                      With --count, also show the share of lines matched
+  --highlight-only               Print whole files with matches highlighted
+  --sort-matches                 Print lines with the most matches first
+  --name-only                    Match file paths instead of contents
+  -l, --files-with-matches       Print only the names of files with a match
+  -L, --files-without-match      Print only the names of files without a match
+  --print0                       End file names with NUL (with -l or -L)
+  --null                         End each printed line with NUL instead of a newline
+  --json-lines                   Print one JSON object per matching line
+  --absolute-path                Show files by their full path
+  -h, --no-filename              Never print per-file headers
+  --summary                      Print match counts per file
+  --total                        Print the total number of matches
+  --max-columns <N>              Truncate printed lines to N characters
+  --tab-width <N>                Show tabs as spaces up to every Nth column
+  --max-matches-per-line <N>     Highlight at most N matches on a line
+  --highlight-first              Highlight only the first match on a line
+  --show-function                Note the function each match is in
+  --trim                         Strip trailing whitespace from printed lines
+  --output-file <path>           Write results to <path>
+  --color <when>                 auto, always or never
+  --match-color <color>          Color for highlighted matches
+  --highlight-style <style>      fg, bg or reverse
+  --map-ext <EXT=EXT>            Highlight one extension's syntax as another's
+  -s, --no-messages              Don't report unreadable files
+  --stats                        Print statistics to stderr
+  --progress                     Show progress on stderr
+
+  --help                         Print this help
+  --version                      Print the version
+  --                             Treat the remaining arguments as paths
+";
+
+/// Errors reported by looneygrep.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LooneygrepError {
+    /// The configuration has missing or conflicting options.
+    InvalidConfig(&'static str),
+    /// This many files or URLs could not be read and were skipped.
+    UnreadableSources(usize),
+    /// The search stopped early, e.g. because output could not be written.
+    SearchFailed(String),
+}
+
+impl fmt::Display for LooneygrepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LooneygrepError::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            LooneygrepError::UnreadableSources(count) => write!(f, "{} file(s) could not be read", count),
+            LooneygrepError::SearchFailed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for LooneygrepError {}
+
+impl From<io::Error> for LooneygrepError {
+    fn from(err: io::Error) -> Self {
+        LooneygrepError::SearchFailed(err.to_string())
+    }
+}
+
+impl From<Box<dyn Error>> for LooneygrepError {
+    fn from(err: Box<dyn Error>) -> Self {
+        LooneygrepError::SearchFailed(err.to_string())
+    }
+}
+
+/// How `--replace-case` rewrites each match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplaceCase {
+    Upper,
+    Lower,
+    /// Uppercase the first letter of each word and lowercase the rest.
+    Title,
+}
+
+impl ReplaceCase {
+    /// Returns `text` converted to this case.
+    pub fn apply(self, text: &str) -> String {
+        match self {
+            ReplaceCase::Upper => text.to_uppercase(),
+            ReplaceCase::Lower => text.to_lowercase(),
+            ReplaceCase::Title => {
+                let mut result = String::with_capacity(text.len());
+                let mut word_start = true;
+                for c in text.chars() {
+                    if word_start {
+                        result.extend(c.to_uppercase());
+                    } else {
+                        result.extend(c.to_lowercase());
+                    }
+                    word_start = !c.is_alphanumeric();
+                }
+                result
+            }
+        }
+    }
+}
+
+/// How search results are printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The human-readable preview with line numbers, highlighting and context.
+    #[default]
+    Text,
+    /// One JSON object per matching line (`path`, `line_number`, `line`),
+    /// printed as soon as it is found.
+    JsonLines,
+}
+
+/// How matches are highlighted, as chosen with `--highlight-style`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HighlightStyle {
+    /// Matches are drawn in the match color.
+    #[default]
+    Fg,
+    /// Matches get the match color as their background.
+    Bg,
+    /// Matches are drawn in reverse video, whatever the surrounding colors.
+    Reverse,
+}
+
+/// Whether output should be colored, as chosen with `--color`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// Color only when standard output is a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Options that take a value, so a value such as `--replace-with --help` is
+/// not mistaken for a flag.
+const VALUE_OPTIONS: [&str; 33] = [
+    "--backup-dir", "--color", "--context", "--context-separator", "--encoding", "--exclude-dir",
+    "--files-from", "--head", "--header", "--highlight-style", "--insert-after", "--insert-before",
+    "--map-ext", "--match-color", "--max-columns", "--max-depth", "--max-matches-per-line",
+    "--max-redirects", "--output", "--output-file", "--pre", "--range", "--replace-case",
+    "--replace-count", "--replace-if-followed-by", "--replace-if-preceded-by", "--replace-log",
+    "--replace-regex", "--replace-with", "--retries", "--tab-width", "--tail", "--url",
+];
+
+/// Name of the optional file holding default options.
+const RC_FILE_NAME: &str = ".looneygreprc";
+
+/// Minimum time between updates of the `--progress` line.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait before the first retry of a failed `--url` request.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Default options read from a `.looneygreprc` TOML file.
+///
+/// Keys mirror the long command-line flags, e.g.:
+///
+/// ```toml
+/// ignore-case = true
+/// context = 2
+/// color = "always"
+/// ```
+///
+/// An unknown key is an error, so a typo doesn't go unnoticed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct RcDefaults {
+    ignore_case: bool,
+    context: usize,
+    color: ColorChoice,
+    word_regexp: bool,
+    regex: bool,
+}
+
+impl RcDefaults {
+    /// Loads defaults from `./.looneygreprc`, falling back to `$HOME/.looneygreprc`.
+    ///
+    /// Only the first file found is used. If neither exists, built-in defaults apply.
+    /// Unit tests always get the built-in defaults, so they don't depend on the
+    /// rc files of whoever runs them.
+    fn load() -> Result<RcDefaults, String> {
+        if cfg!(test) {
+            return Ok(RcDefaults::default());
+        }
+        let mut candidates = vec![PathBuf::from(RC_FILE_NAME)];
+        if let Ok(home) = env::var("HOME") {
+            candidates.push(Path::new(&home).join(RC_FILE_NAME));
+        }
+        RcDefaults::load_from(&candidates)
+    }
+
+    /// Loads defaults from the first of `candidates` that is a file. The error
+    /// names the file, and for a bad file the offending key or value.
+    fn load_from(candidates: &[PathBuf]) -> Result<RcDefaults, String> {
+        match candidates.iter().find(|path| path.is_file()) {
+            Some(path) => {
+                let contents =
+                    fs::read_to_string(path).map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+                RcDefaults::parse(&contents).map_err(|err| format!("Invalid {}: {}", path.display(), err.message()))
+            }
+            None => Ok(RcDefaults::default()),
+        }
+    }
+
+    /// Parses the TOML contents of a `.looneygreprc` file.
+    fn parse(contents: &str) -> Result<RcDefaults, toml::de::Error> {
+        toml::from_str(contents)
+    }
 }
 
 impl Config {
     /// Builds a `Config` from command-line arguments.
     ///
+    /// Options are resolved in this order, later sources overriding earlier ones:
+    ///
+    /// 1. Built-in defaults
+    /// 2. `.looneygreprc` in the current directory, or else in `$HOME`
+    /// 3. The `IGNORE_CASE` environment variable
+    /// 4. Explicit command-line flags
+    ///
     /// # Arguments
     ///
     /// * `args` - An iterator over command-line arguments.
     ///
     /// # Errors
     ///
-    /// Returns an error if required arguments are missing or invalid.
+    /// Returns an error if required arguments are missing or invalid, or if the
+    /// `.looneygreprc` in use can't be read or has an unknown key or bad value.
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use looneygrep::Config;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// let config = Config::build(std::env::args())?;
+    /// # Ok(())
+    /// # }
     /// ```
     pub fn build(mut args: impl Iterator<Item = String>,
-    ) -> Result<Config, &'static str> {
+    ) -> Result<Config, Cow<'static, str>> {
         args.next(); // Skip program name
         let query = match args.next() {
             Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
+            None => return Err("Didn't get a query string".into()),
         };
-        let mut file_path = String::new();
+        // --help and --version win over everything else, wherever a flag can
+        // appear. In the query's place they only count when given alone, so
+        // `lg --help notes.txt` searches for "--help".
+        let args: Vec<String> = args.collect();
+        let flags = flag_arguments(&args);
+        let wants = |flag: &str| (query == flag && args.is_empty()) || flags.contains(&flag);
+        if wants("--help") {
+            return Ok(Config { help: true, ..Default::default() });
+        }
+        if wants("--version") {
+            return Ok(Config { version: true, ..Default::default() });
+        }
+        let mut args = args.into_iter();
+        let defaults = RcDefaults::load()?;
+        let mut paths = Vec::new();
         let mut url = None;
-        let mut ignore_case = env::var("IGNORE_CASE").is_ok();
+        let mut ignore_case = defaults.ignore_case || env::var("IGNORE_CASE").is_ok();
         let mut replace = false;
-        let mut context = 0;
+        let mut context = defaults.context;
+        let mut line_range = None;
+        let mut head = None;
+        let mut tail = None;
+        let mut mark_file_edges = false;
+        let mut context_separator = None;
+        let mut no_context_separator = false;
         let mut search_all = false;
+        let mut count = false;
+        let mut percent = false;
+        let mut total = false;
+        let mut summary = false;
+        let mut absolute_path = false;
+        let mut no_filename = false;
+        let mut recursive = false;
+        let mut follow_symlinks = false;
+        let mut max_depth = None;
+        let mut progress = false;
+        let mut match_color = None;
+        let mut highlight_style = HighlightStyle::Fg;
+        let mut line_regexp = false;
+        let mut starts_with = false;
+        let mut ends_with = false;
+        let mut trim = false;
+        let mut replace_with = None;
+        let mut yes = false;
+        let mut mmap = false;
+        let mut encoding = None;
+        let mut output = None;
+        let mut max_columns = None;
+        let mut tab_width = None;
+        let mut max_matches_per_line = None;
+        let mut show_function = false;
+        let mut headers = Vec::new();
+        let mut max_redirects = None;
+        let mut retries = 0;
+        let mut output_file = None;
+        let mut pre = None;
+        let mut word_regexp = defaults.word_regexp;
+        let mut regex = defaults.regex;
+        let mut multiline = false;
+        let mut dotall = false;
+        let mut substitution = None;
+        let mut output_format = OutputFormat::Text;
+        let mut color = defaults.color;
+        let mut highlight_only = false;
+        let mut sort_matches = false;
+        let mut diff = false;
+        let mut backup = false;
+        let mut force = false;
+        let mut insert_before = None;
+        let mut insert_after = None;
+        let mut replace_if_preceded_by = None;
+        let mut replace_if_followed_by = None;
+        let mut backup_dir = None;
+        let mut replace_log = None;
+        let mut replace_report = false;
+        let mut replace_overview = false;
+        let mut replace_whole_line = false;
+        let mut replace_case = None;
+        let mut preserve_case = false;
+        let mut replace_count = None;
+        let mut exclude_dirs = Vec::new();
+        let mut ext_map = Vec::new();
+        let mut stats = false;
+        let mut no_messages = false;
+        let mut files_from = None;
+        let mut replace_interactive = false;
+        let mut files_with_matches = false;
+        let mut files_without_match = false;
+        let mut name_only = false;
+        let mut print0 = false;
+        let mut null = false;
         while let Some(arg) = args.next() {
-            if arg == "--replace" {
+            if arg == "--" {
+                // Everything after `--` is a path, even if it looks like a flag
+                paths.extend(args.by_ref());
+            } else if arg == "--replace" {
                 replace = true;
             } else if arg == "--ignore-case" {
                 ignore_case = true;
+            } else if arg == "--no-ignore-case" {
+                ignore_case = false;
             } else if arg == "--url" {
                 url = args.next();
             } else if arg == "--context" {
                 context = args.next().and_then(|n| n.parse().ok()).unwrap_or(0);
+            } else if arg == "--range" {
+                let value = args.next().ok_or("Missing value for --range")?;
+                line_range = Some(parse_line_range(&value).ok_or("Invalid --range, expected START:END")?);
+            } else if arg == "--head" {
+                let n = args.next().and_then(|n| n.parse().ok());
+                head = Some(n.ok_or("Invalid --head value")?);
+            } else if arg == "--tail" {
+                let n = args.next().and_then(|n| n.parse().ok());
+                tail = Some(n.ok_or("Invalid --tail value")?);
+            } else if arg == "--mark-file-edges" {
+                mark_file_edges = true;
+            } else if arg == "--context-separator" {
+                context_separator = Some(args.next().ok_or("Missing value for --context-separator")?);
+            } else if arg == "--no-context-separator" {
+                no_context_separator = true;
             } else if arg == "--all" {
                 search_all = true;
+            } else if arg == "--count" {
+                count = true;
+            } else if arg == "--percent" {
+                percent = true;
+            } else if arg == "--files-with-matches" || arg == "-l" {
+                files_with_matches = true;
+            } else if arg == "--files-without-match" || arg == "-L" {
+                files_without_match = true;
+            } else if arg == "--name-only" {
+                name_only = true;
+            } else if arg == "--null" {
+                null = true;
+            } else if arg == "--print0" {
+                print0 = true;
+            } else if arg == "--absolute-path" {
+                absolute_path = true;
+            } else if arg == "--no-filename" || arg == "-h" {
+                no_filename = true;
+            } else if arg == "--summary" {
+                summary = true;
+            } else if arg == "--total" {
+                total = true;
+            } else if arg == "--recursive" {
+                search_all = true;
+                recursive = true;
+            } else if arg == "--follow-symlinks" {
+                follow_symlinks = true;
+            } else if arg == "--max-depth" {
+                let n = args.next().and_then(|n| n.parse().ok());
+                max_depth = Some(n.ok_or("Invalid --max-depth value")?);
+            } else if arg == "--progress" {
+                progress = true;
+            } else if arg == "--no-messages" || arg == "-s" {
+                no_messages = true;
+            } else if arg == "--stats" {
+                stats = true;
+            } else if arg == "--match-color" {
+                let color = args.next().ok_or("Missing value for --match-color")?;
+                match_color = Some(parse_color(&color).ok_or("Invalid --match-color value")?);
+            } else if arg == "--line-regexp" || arg == "-x" {
+                line_regexp = true;
+            } else if arg == "--starts-with" {
+                starts_with = true;
+            } else if arg == "--ends-with" {
+                ends_with = true;
+            } else if arg == "--trim" {
+                trim = true;
+            } else if arg == "--replace-with" {
+                replace_with = Some(args.next().ok_or("Missing value for --replace-with")?);
+            } else if arg == "--sort-matches" {
+                sort_matches = true;
+            } else if arg == "--highlight-only" {
+                highlight_only = true;
+            } else if arg == "--diff" {
+                diff = true;
+            } else if arg == "--insert-before" {
+                insert_before = Some(args.next().ok_or("Missing value for --insert-before")?);
+                replace = true;
+            } else if arg == "--insert-after" {
+                insert_after = Some(args.next().ok_or("Missing value for --insert-after")?);
+                replace = true;
+            } else if arg == "--replace-if-preceded-by" {
+                replace_if_preceded_by = Some(args.next().ok_or("Missing value for --replace-if-preceded-by")?);
+                replace = true;
+            } else if arg == "--replace-if-followed-by" {
+                replace_if_followed_by = Some(args.next().ok_or("Missing value for --replace-if-followed-by")?);
+                replace = true;
+            } else if arg == "--force" {
+                force = true;
+            } else if arg == "--backup" {
+                backup = true;
+            } else if arg == "--backup-dir" {
+                backup_dir = Some(args.next().ok_or("Missing value for --backup-dir")?);
+                backup = true;
+            } else if arg == "--replace-log" {
+                replace_log = Some(args.next().ok_or("Missing value for --replace-log")?);
+            } else if arg == "--replace-report" {
+                replace_report = true;
+            } else if arg == "--replace-overview" {
+                replace_overview = true;
+            } else if arg == "--replace-whole-line" {
+                replace_whole_line = true;
+                replace = true;
+            } else if arg == "--highlight-style" {
+                highlight_style = match args.next().ok_or("Missing value for --highlight-style")?.as_str() {
+                    "fg" => HighlightStyle::Fg,
+                    "bg" => HighlightStyle::Bg,
+                    "reverse" => HighlightStyle::Reverse,
+                    _ => return Err("Invalid --highlight-style, expected fg, bg or reverse".into()),
+                };
+            } else if arg == "--replace-case" {
+                replace_case = match args.next().ok_or("Missing value for --replace-case")?.as_str() {
+                    "upper" => Some(ReplaceCase::Upper),
+                    "lower" => Some(ReplaceCase::Lower),
+                    "title" => Some(ReplaceCase::Title),
+                    _ => return Err("Invalid --replace-case, expected upper, lower or title".into()),
+                };
+                replace = true;
+            } else if arg == "--preserve-case" {
+                preserve_case = true;
+                replace = true;
+            } else if arg == "--replace-count" {
+                let value = args.next().ok_or("Missing value for --replace-count")?;
+                replace_count = Some(value.parse().map_err(|_| "Invalid --replace-count value")?);
+            } else if arg == "--yes" || arg == "-y" {
+                yes = true;
+            } else if arg == "--mmap" {
+                mmap = true;
+            } else if arg == "--encoding" {
+                let label = args.next().ok_or("Missing value for --encoding")?;
+                encoding = Some(Encoding::for_label(label.as_bytes()).ok_or("Unknown --encoding label")?);
+            } else if arg == "--output" {
+                output = Some(args.next().ok_or("Missing value for --output")?);
+            } else if arg == "--max-columns" {
+                let n = args.next().and_then(|n| n.parse().ok()).filter(|&n| n > 0);
+                max_columns = Some(n.ok_or("Invalid --max-columns value")?);
+            } else if arg == "--tab-width" {
+                let value = args.next().ok_or("Missing value for --tab-width")?;
+                tab_width = Some(value.parse().ok().filter(|&n| n > 0).ok_or("Invalid --tab-width value")?);
+            } else if arg == "--highlight-first" {
+                max_matches_per_line = Some(1);
+            } else if arg == "--max-matches-per-line" {
+                let n = args.next().and_then(|n| n.parse().ok());
+                max_matches_per_line = Some(n.ok_or("Invalid --max-matches-per-line value")?);
+            } else if arg == "--show-function" {
+                show_function = true;
+            } else if arg == "--map-ext" {
+                let mapping = args.next().ok_or("Missing value for --map-ext")?;
+                ext_map.push(parse_ext_mapping(&mapping).ok_or("Malformed --map-ext, expected EXT=EXT")?);
+            } else if arg == "--header" {
+                let header = args.next().ok_or("Missing value for --header")?;
+                headers.push(parse_header(&header).ok_or("Malformed --header, expected \"Name: Value\"")?);
+            } else if arg == "--no-redirect" {
+                max_redirects = Some(0);
+            } else if arg == "--max-redirects" {
+                let n = args.next().and_then(|n| n.parse().ok());
+                max_redirects = Some(n.ok_or("Invalid --max-redirects value")?);
+            } else if arg == "--retries" {
+                let n = args.next().and_then(|n| n.parse().ok());
+                retries = n.ok_or("Invalid --retries value")?;
+            } else if arg == "--pre" {
+                pre = Some(args.next().ok_or("Missing value for --pre")?);
+            } else if arg == "--output-file" {
+                output_file = Some(args.next().ok_or("Missing value for --output-file")?);
+            } else if arg == "--json-lines" {
+                output_format = OutputFormat::JsonLines;
+            } else if arg == "--color" {
+                color = match args.next().ok_or("Missing value for --color")?.as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    _ => return Err("Invalid --color, expected auto, always or never".into()),
+                };
+            } else if arg == "--replace-interactive" {
+                replace_interactive = true;
+                replace = true;
+            } else if arg == "--files-from" {
+                files_from = Some(args.next().ok_or("Missing value for --files-from")?);
+            } else if arg == "--exclude-dir" {
+                exclude_dirs.push(args.next().ok_or("Missing value for --exclude-dir")?);
+            } else if arg == "--word-regexp" || arg == "-w" {
+                word_regexp = true;
+            } else if arg == "--no-word-regexp" {
+                word_regexp = false;
+            } else if arg == "--multiline" {
+                multiline = true;
+                regex = true;
+            } else if arg == "--dotall" {
+                dotall = true;
+                multiline = true;
+                regex = true;
+            } else if arg == "--regex" {
+                regex = true;
+            } else if arg == "--no-regex" {
+                regex = false;
+            } else if arg == "--replace-regex" {
+                let expr = args.next().ok_or("Missing value for --replace-regex")?;
+                substitution = Some(parse_substitution(&expr).ok_or("Invalid --replace-regex, expected s/pattern/replacement/")?);
             } else {
-                file_path = arg;
+                paths.push(arg);
             }
         }
-        if !search_all && file_path.is_empty() && url.is_none() {
-            return Err("Didn't get a file path or URL");
+        // With --replace-regex the pattern comes from the s/// expression, so the
+        // first positional argument is a path rather than the query.
+        let mut query = query;
+        if let Some((pattern, replacement, flags_ignore_case)) = substitution {
+            paths.insert(0, std::mem::replace(&mut query, pattern));
+            replace_with = Some(replacement);
+            regex = true;
+            replace = true;
+            ignore_case |= flags_ignore_case;
+        }
+        let file_path = paths.first().cloned().unwrap_or_default();
+        let config = Config {
+            query,
+            file_path,
+            paths,
+            ignore_case,
+            replace,
+            url,
+            context,
+            line_range,
+            head,
+            tail,
+            mark_file_edges,
+            context_separator,
+            no_context_separator,
+            search_all,
+            count,
+            percent,
+            total,
+            summary,
+            absolute_path,
+            no_filename,
+            recursive,
+            follow_symlinks,
+            max_depth,
+            progress,
+            match_color,
+            highlight_style,
+            line_regexp,
+            starts_with,
+            ends_with,
+            trim,
+            replace_with,
+            yes,
+            mmap,
+            encoding,
+            output,
+            max_columns,
+            tab_width,
+            max_matches_per_line,
+            show_function,
+            headers,
+            max_redirects,
+            retries,
+            output_file,
+            pre,
+            word_regexp,
+            regex,
+            multiline,
+            dotall,
+            output_format,
+            help: false,
+            version: false,
+            color,
+            highlight_only,
+            sort_matches,
+            diff,
+            backup,
+            force,
+            insert_before,
+            insert_after,
+            replace_if_preceded_by,
+            replace_if_followed_by,
+            backup_dir,
+            replace_log,
+            replace_report,
+            replace_overview,
+            replace_whole_line,
+            replace_case,
+            preserve_case,
+            replace_count,
+            files_from,
+            replace_interactive,
+            exclude_dirs,
+            ext_map,
+            files_with_matches,
+            files_without_match,
+            name_only,
+            print0,
+            null,
+            stats,
+            no_messages,
+        };
+        if let Err(LooneygrepError::InvalidConfig(message)) = config.validate() {
+            return Err(message.into());
         }
-        Ok(Config { query, file_path, ignore_case, replace, url, context, search_all })
+        Ok(config)
     }
-}
 
-/// Runs the search with the given configuration.
-///
-/// If `search_all` is set, searches all files in the current directory.
-/// If `url` is set, searches the contents of the web page.
-/// Otherwise, searches the specified file.
-///
-/// # Errors
-/// Returns an error if the file or URL cannot be read.
-///
-/// # Example
-/// ```rust
-/// use looneygrep::{Config, run};
-/// let config = Config { /* ... */ };
-/// run(config).unwrap();
-/// ```
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    if config.search_all {
-        use std::fs;
-
-        let entries = fs::read_dir(".")?;
-        for entry in entries {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                let file_path = path.to_string_lossy().to_string();
-                let mut file_config = Config {
-                    file_path: file_path.clone(),
-                    url: None,
-                    ..config.clone()
-                };
-                println!("\n=== Searching in file: {} ===", file_path);
-                // Call a helper to search a single file
-                search_file(&mut file_config)?;
-            }
+    /// Checks that the options in this configuration don't conflict.
+    ///
+    /// [`Config::build`] always validates, but a `Config` built by hand may not
+    /// be, so [`run`] checks again before searching.
+    ///
+    /// # Errors
+    /// Returns [`LooneygrepError::InvalidConfig`] describing the first problem found.
+    pub fn validate(&self) -> Result<(), LooneygrepError> {
+        let invalid = |message| Err(LooneygrepError::InvalidConfig(message));
+        if self.query.is_empty() {
+            return invalid("Query string must not be empty");
+        }
+        if self.regex && build_regex(self).is_err() {
+            return invalid("Invalid regular expression");
+        }
+        let path_count = usize::max(self.paths.len(), usize::from(!self.file_path.is_empty()));
+        if !self.search_all && path_count == 0 && self.url.is_none() && self.files_from.is_none() {
+            return invalid("Didn't get a file path or URL");
+        }
+        if self.search_all && self.url.is_some() {
+            return invalid("--url can't be combined with --all");
+        }
+        if self.replace && self.url.is_some() && self.output.is_none() {
+            return invalid("--replace with --url requires --output");
         }
-        return Ok(());
+        if (self.starts_with || self.ends_with) && (self.regex || self.line_regexp) {
+            return invalid("--starts-with and --ends-with can't be combined with --regex or --line-regexp");
+        }
+        if self.starts_with && self.ends_with {
+            return invalid("--starts-with can't be combined with --ends-with");
+        }
+        if self.multiline && (self.replace_if_preceded_by.is_some() || self.replace_if_followed_by.is_some()) {
+            return invalid("--replace-if-preceded-by and --replace-if-followed-by can't be used with --multiline");
+        }
+        if self.multiline
+            && (self.line_range.is_some()
+                || self.highlight_only
+                || self.sort_matches
+                || self.output_format == OutputFormat::JsonLines)
+        {
+            return invalid("--range, --highlight-only, --sort-matches and --json-lines can't be used with --multiline");
+        }
+        if self.multiline && self.replace_whole_line {
+            return invalid("--replace-whole-line can't be used with --multiline");
+        }
+        if self.multiline && !self.regex {
+            return invalid("--multiline requires --regex");
+        }
+        if self.max_depth == Some(0) {
+            return invalid("--max-depth must be at least 1 (the starting directory only)");
+        }
+        if self.head.is_some() && self.tail.is_some() {
+            return invalid("--head can't be combined with --tail");
+        }
+        if self.multiline && (self.head.is_some() || self.tail.is_some()) {
+            return invalid("--head and --tail can't be used with --multiline");
+        }
+        if self.percent && (!self.count || self.multiline) {
+            return invalid("--percent requires --count, and can't be used with --multiline");
+        }
+        if self.dotall && !self.multiline {
+            return invalid("--dotall requires --multiline");
+        }
+        if self.pre.is_some() && self.replace {
+            return invalid("--replace can't be used with --pre");
+        }
+        if self.output_format == OutputFormat::JsonLines && self.replace {
+            return invalid("--json-lines can't be combined with --replace");
+        }
+        if self.diff && !self.replace {
+            return invalid("--diff requires --replace");
+        }
+        if self.diff && (self.replace_interactive || self.multiline || self.url.is_some()) {
+            return invalid("--diff can't be combined with --replace-interactive, --multiline or --url");
+        }
+        if self.highlight_only && self.replace {
+            return invalid("--highlight-only can't be combined with --replace");
+        }
+        if self.name_only && (self.replace || self.url.is_some()) {
+            return invalid("--name-only can't be combined with --replace or --url");
+        }
+        if self.files_with_matches && self.files_without_match {
+            return invalid("--files-with-matches can't be combined with --files-without-match");
+        }
+        if self.print0 && !self.files_with_matches && !self.files_without_match {
+            return invalid("--print0 requires --files-with-matches or --files-without-match");
+        }
+        if self.insertion().is_some() && (self.replace_with.is_some() || self.replace_case.is_some() || self.replace_whole_line) {
+            return invalid("--insert-before and --insert-after can't be combined with other replacement options");
+        }
+        if self.preserve_case && (self.replace_case.is_some() || self.insertion().is_some() || self.replace_whole_line) {
+            return invalid("--preserve-case can't be combined with --replace-case, --insert-before, --insert-after or --replace-whole-line");
+        }
+        if self.preserve_case && self.replace_with.is_none() {
+            return invalid("--preserve-case requires --replace-with");
+        }
+        if self.yes && self.replace && self.replace_with.is_none() && self.replace_case.is_none() && self.insertion().is_none() {
+            return invalid("--yes requires --replace-with");
+        }
+        // As a filter the replaced text is the output, so no other output mode applies
+        if self.is_stdin_filter()
+            && (self.count
+                || self.diff
+                || self.files_with_matches
+                || self.files_without_match
+                || self.highlight_only
+                || self.output.is_some()
+                || self.output_format == OutputFormat::JsonLines)
+        {
+            return invalid("--count, --diff, --files-with-matches, --files-without-match, --highlight-only, --output and --json-lines can't be used when filtering standard input with a replacement (use --output-file to save the result)");
+        }
+        if self.output.is_some()
+            && (self.search_all || self.files_from.is_some() || path_count + usize::from(self.url.is_some()) > 1)
+        {
+            return invalid("--output can only be used when searching a single file or URL");
+        }
+        Ok(())
     }
 
-    // ...existing code for single file or URL...
-    if let Some(url) = &config.url {
-        let body = fetch_url(url)?;
-        search_contents(&body, &config, "<web page>")?;
-    } else {
-        let contents = fs::read_to_string(&config.file_path)?;
-        search_contents(&contents, &config, &config.file_path)?;
+    /// Returns the matcher deciding which matches `--replace` changes: `matcher`
+    /// itself, narrowed by `--replace-if-preceded-by` and `--replace-if-followed-by`.
+    fn replace_matcher(&self, matcher: &Matcher) -> Matcher {
+        if self.replace_if_preceded_by.is_none() && self.replace_if_followed_by.is_none() {
+            return matcher.clone();
+        }
+        Matcher::Surrounded {
+            inner: Box::new(matcher.clone()),
+            preceded_by: self.replace_if_preceded_by.clone(),
+            followed_by: self.replace_if_followed_by.clone(),
+        }
     }
-    Ok(())
-}
 
-// Helper to search a single file
-fn search_file(config: &mut Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(&config.file_path)?;
-    search_contents(&contents, config, &config.file_path)
-}
+    /// Returns the text that replaces each match.
+    fn replacement(&self) -> &str {
+        self.replace_with.as_deref().unwrap_or(DEFAULT_REPLACEMENT)
+    }
 
-// Helper to search contents (used for both file and URL)
-fn search_contents(contents: &str, config: &Config, file_path: &str) -> Result<(), Box<dyn Error>> {
-    let mut lines: Vec<String> = contents.lines().map(|l| l.to_string()).collect();
-    let mut changed = false;
+    /// Returns the text `--insert-before` and `--insert-after` put around each
+    /// match, or `None` if matches are substituted instead.
+    fn insertion(&self) -> Option<(&str, &str)> {
+        if self.insert_before.is_none() && self.insert_after.is_none() {
+            return None;
+        }
+        Some((self.insert_before.as_deref().unwrap_or(""), self.insert_after.as_deref().unwrap_or("")))
+    }
 
-    // Find matches
-    let matches: Vec<(usize, String)> = lines.iter()
-        .enumerate()
-        .filter(|(_, line)| {
-            if config.ignore_case {
-                line.to_lowercase().contains(&config.query.to_lowercase())
-            } else {
-                line.contains(&config.query)
-            }
-        })
-        .map(|(i, l)| (i, l.clone()))
-        .collect();
+    /// Returns the config for searching a source of `line_count` lines, with
+    /// `--head` or `--tail` turned into the matching part of `--range`.
+    fn for_line_count(&self, line_count: usize) -> Cow<'_, Config> {
+        let limit = match (self.head, self.tail) {
+            (Some(n), _) => (1, n),
+            (_, Some(n)) => (line_count.saturating_sub(n) + 1, usize::MAX),
+            (None, None) => return Cow::Borrowed(self),
+        };
+        let (start, end) = self.line_range.unwrap_or((1, usize::MAX));
+        let line_range = Some((start.max(limit.0), end.min(limit.1)));
+        Cow::Owned(Config { line_range, head: None, tail: None, ..self.clone() })
+    }
 
-    // Live preview (same as before)
-    println!("Preview of matches:");
-    let mut printed = vec![false; lines.len()];
-    let mut lines_printed = 0;
-    let max_lines = 1000;
+    /// Returns true if the line at 0-based `line_idx` is inside `--range`.
+    fn in_range(&self, line_idx: usize) -> bool {
+        self.line_range.is_none_or(|(start, end)| (start..=end).contains(&(line_idx + 1)))
+    }
 
-    for (i, _) in &matches {
-        let start = i.saturating_sub(config.context);
-        let end = usize::min(i + 1 + config.context, lines.len());
-        for line_idx in start..end {
-            if !printed[line_idx] {
-                let line_num = line_idx + 1;
-                if line_idx == *i {
-                    let highlighted = highlight_all_matches(&lines[line_idx], &config.query, config.ignore_case);
-                    println!("{}: {}", line_num, syntax_highlight_line(&highlighted, file_path));
-                } else {
-                    println!("{}: {}", line_num, syntax_highlight_line(&lines[line_idx], file_path));
-                }
-                printed[line_idx] = true;
-            }
+    /// Returns how many matches on one line are highlighted at most.
+    fn max_matches_per_line(&self) -> usize {
+        self.max_matches_per_line.unwrap_or(DEFAULT_MAX_MATCHES_PER_LINE)
+    }
+
+    /// Returns the line printed between match groups, or `None` if disabled.
+    fn context_separator(&self) -> Option<&str> {
+        // With --null the NUL bytes already separate the output
+        if self.no_context_separator || self.null {
+            None
+        } else {
+            Some(self.context_separator.as_deref().unwrap_or(DEFAULT_CONTEXT_SEPARATOR))
         }
-        println!("---");
-        lines_printed += 1;
-        if lines_printed >= max_lines {
-            println!("Output truncated. Too many results.");
-            break;
+    }
+
+    /// Returns what ends each printed line: a NUL byte with `--null`, else a newline.
+    fn line_terminator(&self) -> char {
+        if self.null { '\0' } else { '\n' }
+    }
+
+    /// Returns the ANSI escape used to highlight matches, in the chosen `highlight_style`.
+    fn match_color(&self) -> Cow<'_, str> {
+        let color = self.match_color.as_deref().unwrap_or(DEFAULT_MATCH_COLOR);
+        match self.highlight_style {
+            HighlightStyle::Fg => Cow::Borrowed(color),
+            // Foreground codes (`3x`, `38;...`) become background codes (`4x`, `48;...`)
+            HighlightStyle::Bg => Cow::Owned(color.replacen("\x1b[3", "\x1b[4", 1)),
+            HighlightStyle::Reverse => Cow::Borrowed(REVERSE_VIDEO),
         }
     }
 
-    if config.replace {
-        if config.url.is_some() {
-            println!("Warning: --replace is not supported when searching a URL. No changes will be made.");
-            return Ok(());
+    /// Returns true if looneygrep runs as a sed-like filter: standard input is
+    /// the only source and a replacement is given (`--replace-with`,
+    /// `--replace-case`, `--insert-before` or `--insert-after`). The replaced
+    /// text is then written out in place of the usual preview, without prompts.
+    pub fn is_stdin_filter(&self) -> bool {
+        let paths = if self.paths.is_empty() { std::slice::from_ref(&self.file_path) } else { &self.paths[..] };
+        paths.len() == 1
+            && paths[0] == "-"
+            && self.url.is_none()
+            && !self.search_all
+            && self.files_from.is_none()
+            && (self.replace_with.is_some() || self.replace_case.is_some() || self.insertion().is_some())
+    }
+
+    /// Returns true if colors should be written to standard output.
+    fn use_color(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => io::stdout().is_terminal(),
         }
-        // Prompt to replace
-        let mut replace_all = false;
-        for (i, line) in matches {
-            if !replace_all {
-                print!(
-                    "Replace in line {}? (y/n/all/quit): {} ",
-                    i + 1,
-                    highlight_all_matches(&line, &config.query, config.ignore_case)
-                );
-                io::stdout().flush()?;
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                match input.trim() {
-                    "y" => {},
-                    "all" => { replace_all = true; },
-                    "n" => { continue; },
-                    "quit" => { break; },
-                    _ => { continue; }
+    }
+}
+
+/// Replacement text used when `--replace-with` is not given.
+const DEFAULT_REPLACEMENT: &str = "<REPLACED>";
+
+/// Line printed between match groups when `--context-separator` is not given.
+const DEFAULT_CONTEXT_SEPARATOR: &str = "---";
+
+/// How many matches on one line are highlighted when `--max-matches-per-line`
+/// is not given. Keeps pathological lines (e.g. thousands of one-character
+/// matches) from swelling the output with escape codes.
+const DEFAULT_MAX_MATCHES_PER_LINE: usize = 500;
+
+/// Default ANSI escape for highlighting matches (red).
+const DEFAULT_MATCH_COLOR: &str = "\x1b[31m";
+
+/// ANSI escape for `--highlight-style reverse`.
+const REVERSE_VIDEO: &str = "\x1b[7m";
+
+/// Lines longer than this suggest a minified or generated file.
+const GENERATED_LINE_LENGTH: usize = 1000;
+
+/// How many leading bytes of a file are checked for a NUL to decide it is binary.
+const BINARY_SNIFF_LEN: u64 = 8192;
+
+/// ANSI escape for line numbers in the preview (green).
+const LINE_NUMBER_COLOR: &str = "\x1b[32m";
+
+/// ANSI escape for the per-file headers (bold cyan).
+const HEADER_COLOR: &str = "\x1b[1;36m";
+
+/// ANSI escape for `--show-function` notes (dim).
+const NOTE_COLOR: &str = "\x1b[2m";
+
+/// Scopes syntect gives the names in definitions that `--show-function` reports.
+const DEFINITION_SCOPES: [&str; 7] = [
+    "entity.name.function",
+    "entity.name.class",
+    "entity.name.struct",
+    "entity.name.enum",
+    "entity.name.trait",
+    "entity.name.impl",
+    "entity.name.namespace",
+];
+
+/// Converts a color given to `--match-color` into an ANSI escape.
+///
+/// Accepts a basic color name (`red`, `green`, `yellow`, `blue`, `magenta`,
+/// `cyan`, `white`, `black`), a 256-color code (`0`-`255`), or a truecolor
+/// hex value (`#rrggbb`).
+fn parse_color(color: &str) -> Option<String> {
+    let code = match color.to_lowercase().as_str() {
+        "black" => 30,
+        "red" => 31,
+        "green" => 32,
+        "yellow" => 33,
+        "blue" => 34,
+        "magenta" => 35,
+        "cyan" => 36,
+        "white" => 37,
+        other => {
+            if let Some(hex) = other.strip_prefix('#') {
+                if hex.len() != 6 {
+                    return None;
                 }
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+                let (r, g, b) = (channel(0)?, channel(2)?, channel(4)?);
+                return Some(format!("\x1b[38;2;{};{};{}m", r, g, b));
             }
-            lines[i] = replace_all_matches(&lines[i], &config.query, "<REPLACED>", config.ignore_case);
-            changed = true;
+            let index: u8 = other.parse().ok()?;
+            return Some(format!("\x1b[38;5;{}m", index));
         }
+    };
+    Some(format!("\x1b[{}m", code))
+}
 
-        // Write changes if any
-        if changed {
-            fs::write(file_path, lines.join("\n"))?;
-            println!("Replacements made and file saved.");
-        } else {
-            println!("No replacements made.");
+/// Parses a `--range` value of the form `START:END` (1-based, inclusive).
+///
+/// Either end may be left out: `100:` runs to the end of the file and `:200`
+/// starts at the first line.
+fn parse_line_range(value: &str) -> Option<(usize, usize)> {
+    let (start, end) = value.split_once(':')?;
+    let start = if start.is_empty() { 1 } else { start.parse().ok()? };
+    let end = if end.is_empty() { usize::MAX } else { end.parse().ok()? };
+    (start >= 1 && start <= end).then_some((start, end))
+}
+
+/// Label used in place of a path for standard input.
+const STDIN_LABEL: &str = "(standard input)";
+
+/// A single input to search: a file, standard input, or a web page.
+#[derive(Clone, Debug, PartialEq)]
+enum Source {
+    File(PathBuf),
+    Stdin,
+    Url(String),
+}
+
+impl Source {
+    /// Returns the name shown in file headers for this source.
+    ///
+    /// With `config.absolute_path`, files are shown by their canonical path, or
+    /// as given if that can't be resolved.
+    fn label(&self, config: &Config) -> String {
+        match self {
+            Source::File(path) if config.absolute_path => fs::canonicalize(path)
+                .unwrap_or_else(|_| path.clone())
+                .to_string_lossy()
+                .to_string(),
+            Source::File(path) => path.to_string_lossy().to_string(),
+            Source::Stdin => STDIN_LABEL.to_string(),
+            Source::Url(url) => url.clone(),
         }
     }
 
-    if config.url.is_none() {
-        print_file_type_note(file_path);
+    /// Reads the full contents of this source, decoding files and standard
+    /// input from `config.encoding` when one is given.
+    fn read(&self, config: &Config) -> Result<String, Box<dyn Error>> {
+        match (self, config.encoding) {
+            (Source::File(path), encoding) if config.pre.is_some() => {
+                let bytes = run_preprocessor(config.pre.as_deref().unwrap_or_default(), path)?;
+                Ok(match encoding {
+                    Some(encoding) => decode(&bytes, encoding),
+                    None => String::from_utf8_lossy(&bytes).into_owned(),
+                })
+            }
+            (Source::File(path), None) => Ok(fs::read_to_string(path)?),
+            (Source::File(path), Some(encoding)) => Ok(decode(&fs::read(path)?, encoding)),
+            (Source::Stdin, None) => {
+                let mut contents = String::new();
+                io::Read::read_to_string(&mut io::stdin(), &mut contents)?;
+                Ok(contents)
+            }
+            (Source::Stdin, Some(encoding)) => {
+                let mut bytes = Vec::new();
+                io::Read::read_to_end(&mut io::stdin(), &mut bytes)?;
+                Ok(decode(&bytes, encoding))
+            }
+            (Source::Url(url), _) => fetch_url(url, config),
+        }
     }
-
-    Ok(())
 }
 
-/// Highlights all matches of the query in a line using ANSI escape codes.
-fn highlight_all_matches(line: &str, query: &str, ignore_case: bool) -> String {
-    if query.is_empty() {
-        return line.to_string();
+impl Config {
+    /// Returns the positional paths (or `file_path`) with `~`, `$VAR` and globs
+    /// expanded, as [`Config::sources`] searches them.
+    fn expanded_paths(&self) -> Vec<String> {
+        let paths = if self.paths.is_empty() && !self.file_path.is_empty() {
+            std::slice::from_ref(&self.file_path)
+        } else {
+            &self.paths[..]
+        };
+        paths.iter().flat_map(|path| expand_glob(&expand_path(path))).collect()
     }
-    let mut result = String::new();
-    let mut last = 0;
-    let (line_cmp, query_cmp) = if ignore_case {
-        (line.to_lowercase(), query.to_lowercase())
-    } else {
-        (line.to_string(), query.to_string())
-    };
-    let mut search_start = 0;
-    while let Some(pos) = line_cmp[search_start..].find(&query_cmp) {
-        let abs_pos = search_start + pos;
-        result.push_str(&line[last..abs_pos]);
-        result.push_str("\x1b[31m"); // Red
-        result.push_str(&line[abs_pos..abs_pos + query.len()]);
-        result.push_str("\x1b[0m");
-        last = abs_pos + query.len();
-        search_start = last;
+
+    /// Collects every source to search, in order: positional paths (`-` meaning
+    /// standard input), then the files listed in `--files-from`, then the
+    /// `--url` page, then the files found by `--all`.
+    fn sources(&self) -> io::Result<Vec<Source>> {
+        let mut sources = Vec::new();
+        let mut visited = HashSet::new();
+        for path in self.expanded_paths() {
+            if path == "-" {
+                sources.push(Source::Stdin);
+            } else if Path::new(&path).is_dir() {
+                // A directory is searched like `--all`, but rooted there
+                let mut files = Vec::new();
+                visited.insert(fs::canonicalize(&path)?);
+                collect_files(Path::new(&path), 1, self, &mut visited, &mut files)?;
+                sources.extend(files.into_iter().map(Source::File));
+            } else {
+                sources.push(Source::File(PathBuf::from(path)));
+            }
+        }
+        if let Some(list) = &self.files_from {
+            let list = fs::read_to_string(expand_path(list))?;
+            sources.extend(parse_file_list(&list).map(|path| Source::File(PathBuf::from(path))));
+        }
+        if let Some(url) = &self.url {
+            sources.push(Source::Url(url.clone()));
+        }
+        if self.search_all {
+            let mut files = Vec::new();
+            visited.insert(fs::canonicalize(".")?);
+            collect_files(Path::new("."), 1, self, &mut visited, &mut files)?;
+            sources.extend(files.into_iter().map(|path| Source::File(strip_dot_prefix(path))));
+        }
+        Ok(sources)
     }
-    result.push_str(&line[last..]);
-    result
 }
 
-/// Replaces all matches of the query in a line, case-sensitive or insensitive.
-fn replace_all_matches(line: &str, query: &str, replacement: &str, ignore_case: bool) -> String {
-    if ignore_case {
-        let mut result = String::new();
-        let mut last = 0;
-        let line_lower = line.to_lowercase();
-        let query_lower = query.to_lowercase();
-        let mut search_start = 0;
-        while let Some(pos) = line_lower[search_start..].find(&query_lower) {
-            let abs_pos = search_start + pos;
-            result.push_str(&line[last..abs_pos]);
-            result.push_str(replacement);
-            last = abs_pos + query.len();
-            search_start = last;
+/// Expands a leading `~` to the home directory and `$VAR` or `${VAR}` to the
+/// variable's value, for paths the shell didn't expand (e.g. quoted).
+///
+/// Unset variables, `~user` and a missing home directory are left as written.
+fn expand_path(path: &str) -> String {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        if let Some(home) = dirs::home_dir() {
+            expanded.push_str(&home.to_string_lossy());
+            rest = &rest[1..];
         }
-        result.push_str(&line[last..]);
-        result
-    } else {
-        line.replace(query, replacement)
     }
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        match env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[dollar..dollar + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    expanded
 }
 
-/// Fetches the contents of a URL using a blocking HTTP request.
+/// Expands a path argument containing glob characters (`*`, `?`, `[`) into the
+/// matching paths, for shells that didn't expand it (quoted, or on Windows).
 ///
-/// # Errors
-/// Returns an error if the request fails.
-fn fetch_url(url: &str) -> Result<String, Box<dyn Error>> {
-    let resp = reqwest::blocking::get(url)?;
-    let body = resp.text()?;
-    Ok(body)
+/// The path is returned unchanged if it has no glob characters, exists as
+/// written, is not a valid pattern, or matches nothing.
+fn expand_glob(path: &str) -> Vec<String> {
+    if !path.contains(['*', '?', '[']) || Path::new(path).exists() {
+        return vec![path.to_string()];
+    }
+    let Ok(entries) = glob::glob(path) else {
+        return vec![path.to_string()];
+    };
+    let matches: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    if matches.is_empty() {
+        vec![path.to_string()]
+    } else {
+        matches
+    }
 }
 
-/// Prints a note about the file type based on its extension.
-fn print_file_type_note(file_path: &str) {
-    if let Some(ext) = std::path::Path::new(file_path).extension().and_then(|e| e.to_str()) {
-        match ext {
-            "rs" => println!("(Rust source file detected)"),
-            "txt" => println!("(Text file detected)"),
-            "md" => println!("(Markdown file detected)"),
-            "html" | "htm" => println!("(HTML file detected)"),
-            "css" => println!("(CSS file detected)"),
-            "json" => println!("(JSON file detected)"),
-            "xml" => println!("(XML file detected)"),
-            "yaml" | "yml" => println!("(YAML file detected)"),
-            "toml" => println!("(TOML file detected)"),
-            "log" => println!("(Log file detected)"),
-            "csv" => println!("(CSV file detected)"),
-            "conf" | "cfg" => println!("(Configuration file detected)"),
-            "sh" => println!("(Shell script detected)"),
-            "bat" => println!("(Batch script detected)"),
-            "php" => println!("(PHP source file detected)"),
-            "java" => println!("(Java source file detected)"),
-            "go" => println!("(Go source file detected)"),
-            "py" => println!("(Python source file detected)"),
-            "js" => println!("(JavaScript source file detected)"),
-            "c" | "h" => println!("(C source/header file detected)"),
-            _ => {}
-        }
-    }
-}
-
-/// Applies syntax highlighting to a line based on the file extension.
-fn syntax_highlight_line(line: &str, file_path: &str) -> String {
-    let ps = SyntaxSet::load_defaults_newlines();
-    let ts = ThemeSet::load_defaults();
-    let ext = std::path::Path::new(file_path)
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("");
-    let syntax = ps.find_syntax_by_extension(ext).unwrap_or_else(|| ps.find_syntax_plain_text());
-    let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
-    let mut highlighted = String::new();
-    for line in LinesWithEndings::from(line) {
-        let ranges: Vec<(Style, &str)> = h.highlight_line(line, &ps).unwrap();
-        highlighted.push_str(&as_24_bit_terminal_escaped(&ranges[..], false));
+/// Removes the leading `./` that walking `.` puts on every path, so `--all`
+/// shows `src/lib.rs` rather than `./src/lib.rs`.
+fn strip_dot_prefix(path: PathBuf) -> PathBuf {
+    match path.strip_prefix(".") {
+        Ok(stripped) => stripped.to_path_buf(),
+        Err(_) => path,
     }
-    highlighted
 }
 
-/// Searches lines using a custom matcher closure.
+/// Set by the Ctrl-C handler; searches stop at the next line or file when it is set.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs a Ctrl-C handler that makes a running search stop early.
 ///
-/// # Arguments
+/// The first Ctrl-C lets the current file finish safely (in particular, a file
+/// being written is never cut off) and ends the run with partial results.
+/// A second Ctrl-C exits immediately.
 ///
-/// * `lines` - An iterator over lines to search.
-/// * `matcher` - A closure that takes a line and returns `true` if it matches.
+/// # Errors
+/// Returns an error if a handler could not be installed.
+pub fn install_interrupt_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("\nInterrupted, finishing the current file... (press Ctrl-C again to quit now)");
+    })
+}
+
+/// Returns true once Ctrl-C has been pressed (see [`install_interrupt_handler`]).
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Returns the paths listed in a `--files-from` file, skipping blank lines and
+/// lines starting with `#`.
+fn parse_file_list(list: &str) -> impl Iterator<Item = &str> {
+    list.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+}
+
+/// Runs the search with the given configuration and returns the number of matches.
 ///
-/// # Returns
+/// Searches every positional path in order (`-` reads standard input, and a
+/// directory is searched like `--all` from there), the files listed in `files_from`, the web page given by `url`, and, if `search_all` is set, all files in the current
+/// directory (and its subdirectories when `recursive` is set).
+/// When more than one source is searched, each gets a header line.
 ///
-/// A vector of matching lines.
+/// # Errors
+/// Returns an error if the configuration is invalid (see [`Config::validate`]).
+/// A file or URL that cannot be read is reported on stderr (unless `no_messages`
+/// is set) and skipped; the run then ends with
+/// [`LooneygrepError::UnreadableSources`] once every other source is searched.
 ///
 /// # Example
-///
-/// ```rust
-/// let lines = vec!["foo", "bar", "baz"];
-/// let matches = search(lines.iter(), |line| line.contains("ba"));
+/// ```rust,no_run
+/// use looneygrep::{Config, run};
+/// let config = Config::build(
+///     ["lg", "foo", "bar.txt"].iter().map(|s| s.to_string()),
+/// ).unwrap();
+/// run(config).unwrap();
+/// ```
+pub fn run(config: Config) -> Result<usize, Box<dyn Error>> {
+    config.validate()?;
+    let matches = match &config.output_file {
+        Some(path) => {
+            // A file never gets colors, so none are added only to be stripped again
+            let mut file = io::BufWriter::new(fs::File::create(expand_path(path))?);
+            run_with_writer(Config { color: ColorChoice::Never, ..config }, &mut file)?
+        }
+        None => {
+            // A terminal gets each line as it is written; pipes and files get buffered output
+            let stdout = io::stdout();
+            if stdout.is_terminal() {
+                run_with_writer(config, &mut stdout.lock())?
+            } else {
+                run_with_writer(config, &mut io::BufWriter::new(stdout.lock()))?
+            }
+        }
+    };
+    Ok(matches)
+}
+
+/// Runs the search like [`run`], but writes all output to `out` and returns
+/// the number of matches.
+///
+/// `output_file` is ignored; colors are stripped unless [`Config::use_color`]
+/// says otherwise. Prompts for `--replace` still read standard input.
+///
+/// # Errors
+/// The same as [`run`], with any other failure reported as
+/// [`LooneygrepError::SearchFailed`].
+///
+/// # Example
+/// ```rust
+/// use looneygrep::{Config, run_with_writer};
+/// let path = std::env::temp_dir().join("looneygrep-run-with-writer-doc.txt");
+/// std::fs::write(&path, "one\ntwo\nthree").unwrap();
+/// let config = Config::build(
+///     ["lg", "t", path.to_str().unwrap(), "--color", "never"].iter().map(|s| s.to_string()),
+/// ).unwrap();
+/// let mut out = Vec::new();
+/// assert_eq!(run_with_writer(config, &mut out).unwrap(), 2);
+/// assert!(String::from_utf8(out).unwrap().contains("3: three"));
+/// ```
+pub fn run_with_writer(config: Config, out: &mut impl Write) -> Result<usize, LooneygrepError> {
+    config.validate()?;
+    if config.is_stdin_filter() {
+        // The output is the replaced text itself, so colors aren't stripped from it
+        let matcher = Matcher::from_config(&config).map_err(|err| LooneygrepError::SearchFailed(err.to_string()))?;
+        let contents = Source::Stdin.read(&config)?;
+        let (text, changed) = filter_replace(&contents, &matcher, &config);
+        match config.encoding {
+            Some(encoding) => out.write_all(&encode(&text, encoding))?,
+            None => out.write_all(text.as_bytes())?,
+        }
+        out.flush()?;
+        return Ok(changed);
+    }
+    let mut out: Box<dyn Write + '_> = if config.use_color() {
+        Box::new(out)
+    } else {
+        Box::new(AnsiStripper::new(out))
+    };
+    let out = out.as_mut();
+    let sources = config.sources()?;
+    let list_files = config.files_with_matches || config.files_without_match;
+    // Replacing across whole directories asks before each file, and leaves binary
+    // and generated files alone unless --force
+    let directory_replace =
+        config.replace && (config.search_all || config.expanded_paths().iter().any(|path| Path::new(path).is_dir()));
+    let matcher = Matcher::from_config(&config).map_err(|err| LooneygrepError::SearchFailed(err.to_string()))?;
+    if config.name_only {
+        // Only paths are matched, so no file is opened
+        let mut matches = 0;
+        for source in &sources {
+            let label = source.label(&config);
+            if matches!(source, Source::File(_)) && matcher.is_match(&label) {
+                writeln!(out, "{}", highlight_all_matches(&label, &matcher, &config.match_color(), config.max_matches_per_line()))?;
+                matches += 1;
+            }
+        }
+        out.flush()?;
+        return Ok(matches);
+    }
+    if directory_replace && !config.diff {
+        // A first pass counts the matching lines in each file, so the scope of
+        // the edits is known before the first prompt
+        let mut per_file = Vec::new();
+        for source in &sources {
+            let Source::File(path) = source else {
+                continue;
+            };
+            let Ok(contents) = read_bulk_replace(path, source, &config) else {
+                continue;
+            };
+            let file_config = config.for_line_count(contents.lines().count());
+            let count =
+                (0..).zip(contents.lines()).filter(|(i, line)| file_config.in_range(*i) && matcher.is_match(line)).count();
+            if count > 0 {
+                let label = match bulk_replace_skip(path, &contents, &config) {
+                    Some(kind) => format!("{} ({}, will be skipped)", source.label(&config), kind),
+                    None => source.label(&config),
+                };
+                per_file.push((label, count));
+            }
+        }
+        if !per_file.is_empty() {
+            let total: usize = per_file.iter().map(|(_, count)| count).sum();
+            write_summary(out, "Files to edit:", &mut per_file)?;
+            writeln!(out, "{} matching lines in {} files.", total, per_file.len())?;
+        }
+    }
+    let show_headers = (config.search_all || sources.len() > 1)
+        && !list_files
+        && !config.diff
+        && !config.no_filename
+        && config.output_format == OutputFormat::Text;
+
+    let started = Instant::now();
+    let mut total_matches = 0;
+    let mut lines_scanned = 0;
+    let mut failed = 0;
+    let mut per_source = Vec::new();
+    let source_count = sources.len();
+    let mut last_progress: Option<Instant> = None;
+    for (searched, source) in sources.into_iter().enumerate() {
+        if interrupted() {
+            writeln!(out, "\nInterrupted: searched {} of {} sources, results are partial.", searched, source_count)?;
+            break;
+        }
+        if config.progress && last_progress.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL) {
+            eprint!("\rSearched {}/{} files...", searched, source_count);
+            last_progress = Some(Instant::now());
+        }
+        let label = source.label(&config);
+        // Memory-mapping only applies to plain files that won't be rewritten.
+        let mapped;
+        let owned;
+        let contents: Result<&str, Box<dyn Error>> = 'read: {
+            match &source {
+                Source::File(path) if config.mmap && !config.replace && config.encoding.is_none() && config.pre.is_none() => {
+                    mapped = match map_file(path) {
+                        Ok(map) => map,
+                        Err(err) => break 'read Err(err.into()),
+                    };
+                    std::str::from_utf8(&mapped).map_err(Into::into)
+                }
+                Source::File(path) if directory_replace => {
+                    owned = match read_bulk_replace(path, &source, &config) {
+                        Ok(text) => text,
+                        Err(err) => break 'read Err(err),
+                    };
+                    Ok(&owned)
+                }
+                _ => {
+                    owned = match source.read(&config) {
+                        Ok(text) => text,
+                        Err(err) => break 'read Err(err),
+                    };
+                    Ok(&owned)
+                }
+            }
+        };
+        // A source that can't be read is reported and skipped, but still fails the run.
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(err) => {
+                failed += 1;
+                if !config.no_messages {
+                    eprintln!("looneygrep: {}: {}", label, err);
+                }
+                continue;
+            }
+        };
+        if show_headers {
+            let name_matches = match &source {
+                Source::File(path) => path.file_name().is_some_and(|name| matcher.is_match(&name.to_string_lossy())),
+                _ => false,
+            };
+            let note = if name_matches { " (filename matches query)" } else { "" };
+            // With --null the header is a record of its own, ended by a NUL like the lines
+            if config.null {
+                write!(out, "{}=== Searching in file: {}{} ===\x1b[0m\0", HEADER_COLOR, label, note)?;
+            } else {
+                writeln!(out, "\n{}=== Searching in file: {}{} ===\x1b[0m", HEADER_COLOR, label, note)?;
+            }
+        }
+        let source_config = match &source {
+            Source::File(path) if directory_replace => {
+                let has_match = contents.lines().any(|line| matcher.is_match(line));
+                let skip = if has_match { bulk_replace_skip(path, contents, &config) } else { None };
+                if let Some(kind) = skip {
+                    writeln!(out, "Warning: {} looks {}, so it won't be changed (use --force to replace anyway).", label, kind)?;
+                }
+                if has_match && skip.is_none() && !config.yes && !config.diff {
+                    out.flush()?;
+                    match prompt(&format!("Edit matches in {}? (y/n/quit): ", label))?.as_str() {
+                        "y" => {}
+                        "quit" => break,
+                        _ => continue,
+                    }
+                }
+                Config { file_path: label.clone(), url: None, replace: config.replace && skip.is_none(), ..config.clone() }
+            }
+            Source::File(_) => Config { file_path: label.clone(), url: None, ..config.clone() },
+            Source::Stdin => {
+                if config.replace {
+                    writeln!(out, "Warning: --replace is not supported when reading standard input. No changes will be made.")?;
+                }
+                Config { file_path: label.clone(), url: None, replace: false, ..config.clone() }
+            }
+            Source::Url(url) => Config { url: Some(url.clone()), ..config.clone() },
+        };
+        let search_name = match &source {
+            Source::Url(_) => "<web page>",
+            _ => &label,
+        };
+        let stats = search_contents(contents, &source_config, search_name, out)?;
+        if config.summary && stats.matches > 0 {
+            per_source.push((label.clone(), stats.matches));
+        }
+        total_matches += stats.matches;
+        lines_scanned += stats.lines_scanned;
+    }
+    if config.progress {
+        // Clear the progress line
+        eprint!("\r\x1b[2K");
+    }
+    if config.summary {
+        write_summary(out, "Matches by file:", &mut per_source)?;
+    }
+    if config.total {
+        writeln!(out, "\nTotal: {}", total_matches)?;
+    }
+    out.flush()?;
+    if config.stats {
+        eprintln!("\nStatistics:");
+        eprintln!("  Sources searched: {}", source_count);
+        eprintln!("  Lines scanned: {}", lines_scanned);
+        eprintln!("  Matches: {}", total_matches);
+        eprintln!("  Elapsed: {:.3}s", started.elapsed().as_secs_f64());
+    }
+    if failed > 0 {
+        return Err(LooneygrepError::UnreadableSources(failed));
+    }
+    Ok(total_matches)
+}
+
+/// One matching line found by [`find_matches`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    /// The file the line is in, as it would be shown in a file header. Standard
+    /// input is `(standard input)` and a web page is its URL.
+    pub path: PathBuf,
+    /// The 1-based line number.
+    pub line_number: usize,
+    /// The line, without its line ending.
+    pub line: String,
+    /// Byte ranges of each match within `line`.
+    pub spans: Vec<Range<usize>>,
+}
+
+/// Searches every source `config` names and returns the matching lines,
+/// without printing anything or changing any file.
+///
+/// The query options (`--regex`, `--word-regexp`, `--ignore-case`, `--range`,
+/// ...) apply as in [`run`]; output, context and replace options are ignored.
+/// With `--multiline` (or `--dotall`) the regex runs over the whole of each
+/// source, and each match is one [`Match`] whose `line` holds every line it
+/// spans, joined by `\n`.
+///
+/// # Errors
+/// Returns [`LooneygrepError::InvalidConfig`] for a bad configuration or for
+/// `--name-only`, which matches paths rather than lines,
+/// [`LooneygrepError::SearchFailed`] if the query can't be compiled or the
+/// sources can't be listed, and [`LooneygrepError::UnreadableSources`] if any
+/// source could not be read.
+///
+/// # Example
+/// ```rust
+/// use looneygrep::{Config, find_matches};
+/// let path = std::env::temp_dir().join("looneygrep-find-matches-doc.txt");
+/// std::fs::write(&path, "one\ntwo two\nthree").unwrap();
+/// let config = Config { query: "two".to_string(), file_path: path.to_string_lossy().into(), ..Default::default() };
+/// let matches = find_matches(&config).unwrap();
+/// assert_eq!(matches.len(), 1);
+/// assert_eq!((matches[0].line_number, matches[0].spans.clone()), (2, vec![0..3, 4..7]));
+/// ```
+pub fn find_matches(config: &Config) -> Result<Vec<Match>, LooneygrepError> {
+    config.validate()?;
+    if config.name_only {
+        return Err(LooneygrepError::InvalidConfig("--name-only matches paths, not lines, so it has no matches to return"));
+    }
+    let matcher = Matcher::from_config(config).map_err(|err| LooneygrepError::SearchFailed(err.to_string()))?;
+    let mut matches = Vec::new();
+    let mut failed = 0;
+    for source in config.sources()? {
+        let Ok(contents) = source.read(config) else {
+            failed += 1;
+            continue;
+        };
+        let path = PathBuf::from(source.label(config));
+        if let (true, Matcher::Regex(regex)) = (config.multiline, &matcher) {
+            // As in `search_multiline`, a match may span several lines
+            for found in regex.find_iter(&contents) {
+                let (line_number, block) = match_block(&contents, found.start(), found.end());
+                let line = trim_line_ending(&contents[block.clone()]);
+                let span = (found.start() - block.start)..(found.end() - block.start).min(line.len());
+                matches.push(Match { path: path.clone(), line_number, line: line.to_string(), spans: std::iter::once(span).collect() });
+            }
+            continue;
+        }
+        let source_config = config.for_line_count(contents.lines().count());
+        for (i, line) in contents.lines().map(trim_line_ending).enumerate() {
+            if !source_config.in_range(i) {
+                continue;
+            }
+            let spans = matcher.find_all(line);
+            if !spans.is_empty() {
+                matches.push(Match {
+                    path: path.clone(),
+                    line_number: i + 1,
+                    line: line.to_string(),
+                    spans: spans.into_iter().map(|(start, end)| start..end).collect(),
+                });
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(LooneygrepError::UnreadableSources(failed));
+    }
+    Ok(matches)
+}
+
+/// Writes the `--summary` table under `heading`: one `count  path` row per
+/// source with matches, most matches first.
+fn write_summary(out: &mut dyn Write, heading: &str, per_source: &mut [(String, usize)]) -> io::Result<()> {
+    per_source.sort_by(|(a_path, a_count), (b_path, b_count)| b_count.cmp(a_count).then(a_path.cmp(b_path)));
+    let width = per_source.first().map_or(1, |(_, count)| count.to_string().len());
+    writeln!(out, "\n{}", heading)?;
+    for (path, count) in per_source.iter() {
+        writeln!(out, "{:>width$}  {}", count, path, width = width)?;
+    }
+    Ok(())
+}
+
+/// Decodes bytes in the given encoding to UTF-8. A byte order mark, if
+/// present, takes precedence over `encoding`.
+fn decode(bytes: &[u8], encoding: &'static Encoding) -> String {
+    encoding.decode(bytes).0.into_owned()
+}
+
+/// Encodes UTF-8 text back into the given encoding.
+///
+/// `encoding_rs` only encodes UTF-16 as UTF-8, so UTF-16 is handled here.
+fn encode(text: &str, encoding: &'static Encoding) -> Vec<u8> {
+    if encoding == UTF_16LE {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    } else if encoding == UTF_16BE {
+        text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+    } else {
+        encoding.encode(text).0.into_owned()
+    }
+}
+
+/// Writes `text` to `path`, re-encoding it when an encoding is given.
+///
+/// A byte order mark on the `original` file is kept, and it decides the
+/// encoding, just as it did when the file was read. The file is replaced
+/// atomically (see [`write_atomic`]).
+fn write_contents(
+    path: &str,
+    text: &str,
+    encoding: Option<&'static Encoding>,
+    original: &str,
+) -> io::Result<()> {
+    let Some(encoding) = encoding else {
+        return write_atomic(Path::new(path), text.as_bytes());
+    };
+    let bom = Encoding::for_bom(&fs::read(original).unwrap_or_default());
+    let encoding = bom.map_or(encoding, |(bom_encoding, _)| bom_encoding);
+    let mut bytes = if bom.is_some() { encode("\u{feff}", encoding) } else { Vec::new() };
+    bytes.extend(encode(text, encoding));
+    write_atomic(Path::new(path), &bytes)
+}
+
+/// Writes `bytes` to a temporary file next to `path` and renames it over
+/// `path`, so the file is never left half-written if the process dies.
+///
+/// The permissions of an existing file are kept, and a read-only file is
+/// refused just as a direct write would be.
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    // Write through a symlink rather than replacing the link itself
+    let path = &fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let name = path.file_name().ok_or_else(|| io::Error::other("not a file path"))?;
+    let metadata = fs::metadata(path).ok();
+    if metadata.as_ref().is_some_and(|m| m.permissions().readonly()) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file is read-only"));
+    }
+    let temp = path.with_file_name(format!(".{}.looneygrep-{}.tmp", name.to_string_lossy(), std::process::id()));
+    let result = (|| {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(bytes)?;
+        if let Some(metadata) = &metadata {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Memory-maps a file for reading, avoiding a copy of its contents.
+fn map_file(path: &Path) -> io::Result<Mmap> {
+    let file = fs::File::open(path)?;
+    // SAFETY: the map is only read, and only for the duration of one search. As
+    // with any mmap, a concurrent truncation by another process is not guarded
+    // against, which is why this path is opt-in via `--mmap`.
+    unsafe { Mmap::map(&file) }
+}
+
+/// Collects the files to search in `dir`, descending into subdirectories when
+/// `config.recursive` is set.
+///
+/// `depth` is the depth of `dir` itself, starting at 1; subdirectories are not
+/// entered once it reaches `config.max_depth`.
+///
+/// Symlinked files are always included. Symlinked directories are only entered
+/// with `config.follow_symlinks`, and `visited` holds the canonical path of every
+/// directory already walked so that symlink cycles are not followed forever.
+fn collect_files(
+    dir: &Path,
+    depth: usize,
+    config: &Config,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let descend = config.recursive && config.max_depth.is_none_or(|max| depth < max);
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        } else if descend && path.is_dir() {
+            if is_excluded_dir(&path, &config.exclude_dirs) {
+                continue;
+            }
+            let is_symlink = fs::symlink_metadata(&path)?.file_type().is_symlink();
+            if is_symlink && !config.follow_symlinks {
+                continue;
+            }
+            if visited.insert(fs::canonicalize(&path)?) {
+                collect_files(&path, depth + 1, config, visited, files)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the directory's name matches one of the `--exclude-dir` patterns.
+fn is_excluded_dir(path: &Path, patterns: &[String]) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    patterns.iter().any(|pattern| glob_match(pattern, name))
+}
+
+/// Matches `text` against a glob pattern where `*` matches any run of
+/// characters and `?` matches exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text index it was tried at, for backtracking.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// One matching line in `--json-lines` output.
+#[derive(Serialize)]
+struct JsonMatch<'a> {
+    path: &'a str,
+    line_number: usize,
+    line: &'a str,
+}
+
+/// Counts gathered while searching one source, reported by `--stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct SearchStats {
+    /// Number of matching lines (with `-l`, 1 if the source matched at all).
+    matches: usize,
+    /// Number of lines examined before the search finished.
+    lines_scanned: usize,
+}
+
+// Helper to search contents (used for both file and URL), returning the number of matching
+// and scanned lines
+//
+// All output goes to `out`; only the interactive replace prompts use stdout directly.
+fn search_contents(
+    contents: &str,
+    config: &Config,
+    file_path: &str,
+    out: &mut dyn Write,
+) -> Result<SearchStats, Box<dyn Error>> {
+    // `lines` handles `\r\n`, but a stray `\r` (e.g. on a last line without `\n`) is dropped too
+    let mut lines: Vec<String> = contents.lines().map(|l| trim_line_ending(l).to_string()).collect();
+    let mut changes = 0;
+    let config = &*config.for_line_count(lines.len());
+
+    let matcher = Matcher::from_config(config)?;
+    if let (true, Matcher::Regex(regex)) = (config.multiline, &matcher) {
+        return search_multiline(contents, regex, config, file_path, out);
+    }
+    if config.count {
+        let count = (0..).zip(&lines).filter(|(i, line)| config.in_range(*i) && matcher.is_match(line)).count();
+        if config.percent {
+            let searched = (0..lines.len()).filter(|&i| config.in_range(i)).count();
+            let share = if searched == 0 { 0.0 } else { count as f64 * 100.0 / searched as f64 };
+            writeln!(out, "{}/{} ({:.1}%)", count, searched, share)?;
+        } else {
+            writeln!(out, "{}", count)?;
+        }
+        return Ok(SearchStats { matches: count, lines_scanned: lines.len() });
+    }
+    if config.files_with_matches || config.files_without_match {
+        // One match is enough to decide, so stop scanning there.
+        let first_match = (0..).zip(&lines).position(|(i, line)| config.in_range(i) && matcher.is_match(line));
+        if first_match.is_some() != config.files_without_match {
+            let name = config.url.as_deref().unwrap_or(file_path);
+            let terminator = if config.print0 { '\0' } else { '\n' };
+            write!(out, "{}{}", name, terminator)?;
+        }
+        return Ok(SearchStats {
+            matches: usize::from(first_match.is_some()),
+            lines_scanned: first_match.map_or(lines.len(), |i| i + 1),
+        });
+    }
+
+    if config.output_format == OutputFormat::JsonLines {
+        let path = config.url.as_deref().unwrap_or(file_path);
+        let mut match_count = 0;
+        for (i, line) in lines.iter().enumerate() {
+            if !config.in_range(i) || !matcher.is_match(line) {
+                continue;
+            }
+            match_count += 1;
+            let record = JsonMatch { path, line_number: i + 1, line };
+            writeln!(out, "{}", serde_json::to_string(&record)?)?;
+            out.flush()?;
+        }
+        return Ok(SearchStats { matches: match_count, lines_scanned: lines.len() });
+    }
+    if config.diff {
+        // Every match is replaced up front, so the diff shows the whole change at once
+        let replace_matcher = config.replace_matcher(&matcher);
+        let mut remaining = config.replace_count;
+        let mut new_lines = lines.clone();
+        let mut match_count = 0;
+        for (i, line) in new_lines.iter_mut().enumerate() {
+            if remaining == Some(0) || !config.in_range(i) || !matcher.is_match(line) {
+                continue;
+            }
+            match_count += 1;
+            if !replace_matcher.is_match(line) {
+                continue;
+            }
+            let (replaced_line, replaced) = replace_line(line, &replace_matcher, config, remaining);
+            *line = replaced_line;
+            remaining = remaining.map(|n| n - replaced);
+        }
+        let missing_newline = !contents.is_empty() && !contents.ends_with('\n');
+        write!(out, "{}", unified_diff(file_path, &lines, &new_lines, missing_newline))?;
+        return Ok(SearchStats { matches: match_count, lines_scanned: lines.len() });
+    }
+    let enclosing = if config.show_function {
+        enclosing_definitions(&lines, file_path, &config.ext_map)
+    } else {
+        Vec::new()
+    };
+    // The --show-function note for the line at `line_idx`, if it is a match
+    let note = |line_idx: usize, is_match: bool| {
+        enclosing
+            .get(line_idx)
+            .and_then(Option::as_ref)
+            .filter(|_| is_match)
+            .map(|(def_idx, name)| format!("in {} (line {})", name, def_idx + 1))
+    };
+    if config.highlight_only {
+        let mut match_count = 0;
+        for (i, line) in lines.iter().enumerate() {
+            let is_match = config.in_range(i) && matcher.is_match(line);
+            match_count += usize::from(is_match);
+            let highlight = Some(&matcher).filter(|_| is_match);
+            write_line(out, i + 1, line, highlight, note(i, is_match).as_deref(), config, file_path)?;
+        }
+        return Ok(SearchStats { matches: match_count, lines_scanned: lines.len() });
+    }
+
+    // Live preview: each match (and its context) is printed as soon as it is found.
+    // Only the replace path needs the full list of matches, so only it buffers them.
+    // Context windows that overlap or touch extend the group already printed;
+    // only a gap between windows starts a new group, after a separator.
+    if !config.null {
+        writeln!(out, "Preview of matches:")?;
+    }
+    let mut matches: Vec<(usize, String)> = Vec::new();
+    let mut match_count = 0;
+    // Lines are printed in order, so everything before this index has already been shown.
+    let mut next_unprinted = 0;
+    let mut lines_printed = 0;
+    let max_lines = 1000;
+    let mut truncated = false;
+    let mut lines_scanned = 0;
+    // Matching lines as (occurrences, index), for --sort-matches
+    let mut busiest = Vec::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        if interrupted() {
+            break;
+        }
+        // Nothing past the end of --range (or --head) can match
+        if config.line_range.is_some_and(|(_, end)| i >= end) {
+            break;
+        }
+        lines_scanned += 1;
+        if !config.in_range(i) || !matcher.is_match(line) {
+            continue;
+        }
+        match_count += 1;
+        if config.replace {
+            matches.push((i, line.clone()));
+        }
+        if config.sort_matches {
+            // Printed once every line has been counted
+            busiest.push((matcher.find_all(line).len(), i));
+            continue;
+        }
+        if truncated {
+            continue;
+        }
+
+        let window_start = i.saturating_sub(config.context);
+        let start = usize::max(window_start, next_unprinted);
+        let end = usize::min(i + 1 + config.context, lines.len());
+        if next_unprinted > 0 && window_start > next_unprinted {
+            if let Some(separator) = config.context_separator() {
+                writeln!(out, "{}", separator)?;
+            }
+        }
+        // Say so when the context window was cut short by the start or end of the file
+        if config.mark_file_edges && i < config.context && start == 0 {
+            writeln!(out, "(top of file)")?;
+        }
+        for (line_idx, context_line) in lines.iter().enumerate().take(end).skip(start) {
+            // A later match can show up as context here, and is highlighted as one
+            let is_match = line_idx == i || (config.in_range(line_idx) && matcher.is_match(context_line));
+            let highlight = Some(&matcher).filter(|_| is_match);
+            let note = note(line_idx, is_match);
+            write_line(out, line_idx + 1, context_line, highlight, note.as_deref(), config, file_path)?;
+        }
+        if config.mark_file_edges && i + config.context >= lines.len() && next_unprinted < end {
+            writeln!(out, "(end of file)")?;
+        }
+        next_unprinted = usize::max(next_unprinted, end);
+        lines_printed += 1;
+        if lines_printed >= max_lines {
+            writeln!(out, "Output truncated. Too many results.")?;
+            // Keep scanning so the match count (and replace list) stays complete.
+            truncated = true;
+        }
+    }
+    // Busiest lines first, each with its own context; ties stay in file order
+    busiest.sort_by_key(|&(occurrences, i)| (Reverse(occurrences), i));
+    for (printed, &(_, i)) in busiest.iter().enumerate() {
+        if printed == max_lines {
+            writeln!(out, "Output truncated. Too many results.")?;
+            break;
+        }
+        if printed > 0 && config.context > 0 {
+            if let Some(separator) = config.context_separator() {
+                writeln!(out, "{}", separator)?;
+            }
+        }
+        let end = usize::min(i + 1 + config.context, lines.len());
+        for (line_idx, context_line) in lines.iter().enumerate().take(end).skip(i.saturating_sub(config.context)) {
+            let is_match = line_idx == i || (config.in_range(line_idx) && matcher.is_match(context_line));
+            let highlight = Some(&matcher).filter(|_| is_match);
+            let note = note(line_idx, is_match);
+            write_line(out, line_idx + 1, context_line, highlight, note.as_deref(), config, file_path)?;
+        }
+    }
+
+    if config.replace {
+        // A web page can't be written back, so it can only be saved to --output
+        if config.url.is_some() && config.output.is_none() {
+            writeln!(out, "Warning: --replace on a URL needs --output <path> to save the result. No changes will be made.")?;
+            return Ok(SearchStats { matches: match_count, lines_scanned });
+        }
+        // Only lines with a match the conditions allow are offered for replacement
+        let matcher = config.replace_matcher(&matcher);
+        matches.retain(|(_, line)| matcher.is_match(line));
+        if config.replace_overview && !matches.is_empty() {
+            let match_total: usize = matches.iter().map(|(_, line)| matcher.find_all(line).len()).sum();
+            let line_numbers: Vec<String> = matches.iter().map(|(i, _)| (i + 1).to_string()).collect();
+            writeln!(out, "Found {} matches in {} lines; starting replacement...", match_total, matches.len())?;
+            writeln!(out, "Lines: {}", line_numbers.join(", "))?;
+            out.flush()?;
+        }
+        // Prompt to replace, unless --yes asked to replace everything up front
+        let mut replace_all = config.yes;
+        // Say up front how many prompts are coming, so "all" can be chosen early
+        if !replace_all && !config.replace_interactive && !matches.is_empty() {
+            writeln!(
+                out,
+                "{} matches found. Replace interactively? The prompt accepts y/n/all/undo/quit, and each for lines with several matches.",
+                matches.len()
+            )?;
+            out.flush()?;
+        }
+        // Matches left to replace under --replace-count
+        let mut remaining = config.replace_count;
+        // Changes as (line number, old lines, new lines) for --replace-log and
+        // --replace-report, written only once the changes are saved
+        let mut log: Vec<(usize, Vec<String>, Vec<String>)> = Vec::new();
+        // Confirmed replacements as (line index, original line, `remaining` before), for `undo`
+        let mut undo: Vec<(usize, String, Option<usize>)> = Vec::new();
+        // Editing in $EDITOR can add or remove lines, moving the matches that follow
+        let mut shift: isize = 0;
+        for (i, line) in matches {
+            let i = i.wrapping_add_signed(shift);
+            // On Ctrl-C, stop replacing but still offer to save what was done so far
+            if remaining == Some(0) || interrupted() {
+                break;
+            }
+            if config.replace_interactive {
+                // An earlier edit may already have changed this line
+                if !lines.get(i).is_some_and(|line| matcher.is_match(line)) {
+                    continue;
+                }
+                let start = i.saturating_sub(config.context);
+                let end = usize::min(i + 1 + config.context, lines.len());
+                out.flush()?;
+                let edited = edit_in_editor(&lines[start..end].join("\n"), &editor_command(), file_path)?;
+                let edited: Vec<String> = edited.lines().map(|l| l.to_string()).collect();
+                if edited[..] != lines[start..end] {
+                    shift += edited.len() as isize - (end - start) as isize;
+                    log.push((start + 1, lines[start..end].to_vec(), edited.clone()));
+                    lines.splice(start..end, edited);
+                    changes += 1;
+                }
+                continue;
+            }
+            // Occurrences picked one by one with `each`; `None` replaces them all
+            let mut chosen = None;
+            if !replace_all {
+                // A line with several matches can have them replaced one by one
+                let spans = if config.replace_whole_line { Vec::new() } else { matcher.find_all(&lines[i]) };
+                let choices = if spans.len() > 1 { "y/n/all/each/undo/quit" } else { "y/n/all/undo/quit" };
+                let question = format!(
+                    "Replace in line {}? ({}): {} ",
+                    i + 1,
+                    choices,
+                    highlight_all_matches(&line, &matcher, &config.match_color(), config.max_matches_per_line())
+                );
+                let input = loop {
+                    let input = prompt(&question)?;
+                    if input != "undo" {
+                        break input;
+                    }
+                    // Nothing is written until the end, so undoing just restores the pending line
+                    match undo.pop() {
+                        Some((j, original, previous_remaining)) => {
+                            writeln!(out, "Undid the replacement in line {}.", j + 1)?;
+                            lines[j] = original;
+                            remaining = previous_remaining;
+                            log.pop();
+                            changes -= 1;
+                        }
+                        None => writeln!(out, "Nothing to undo.")?,
+                    }
+                    out.flush()?;
+                };
+                match input.as_str() {
+                    "y" => {},
+                    "all" => { replace_all = true; },
+                    "each" if spans.len() > 1 => {
+                        chosen = Some(choose_occurrences(&lines[i], &spans, remaining, &config.match_color())?);
+                    },
+                    "n" => { continue; },
+                    "quit" => { break; },
+                    _ => { continue; }
+                }
+            }
+            let (replaced_line, replaced) = match chosen {
+                Some(spans) if spans.is_empty() => continue,
+                Some(spans) => (replace_chosen(&lines[i], &spans, &matcher, config), spans.len()),
+                None => replace_line(&lines[i], &matcher, config, remaining),
+            };
+            log.push((i + 1, vec![lines[i].clone()], vec![replaced_line.clone()]));
+            undo.push((i, std::mem::replace(&mut lines[i], replaced_line), remaining));
+            remaining = remaining.map(|n| n - replaced);
+            changes += 1;
+        }
+
+        let ending = line_ending(contents);
+        let mut text = lines.join(ending);
+        if contents.ends_with('\n') {
+            text.push_str(ending);
+        }
+        if save_replacements(&text, contents, changes, config, file_path, out)? {
+            if let Some(log_path) = &config.replace_log {
+                let entries: Vec<String> =
+                    log.iter().map(|(line_num, old, new)| log_entry(file_path, *line_num, old, new)).collect();
+                append_replace_log(&expand_path(log_path), &entries)?;
+            }
+            if config.replace_report {
+                for (line_num, old, new) in &log {
+                    writeln!(out, "{}", report_record(file_path, *line_num, &old.join("\n"), &new.join("\n")))?;
+                }
+            }
+        }
+    }
+
+    if config.url.is_none() && !config.null {
+        print_file_type_note(file_path, out)?;
+    }
+
+    Ok(SearchStats { matches: match_count, lines_scanned })
+}
+
+/// Applies the configured replacement to one matching line, replacing at most
+/// `limit` matches. Returns the new line and how many matches were replaced.
+fn replace_line(line: &str, matcher: &Matcher, config: &Config, limit: Option<usize>) -> (String, usize) {
+    if config.replace_whole_line {
+        (config.replacement().to_string(), 1)
+    } else if let Some(case) = config.replace_case {
+        replace_matches_with(line, matcher, limit, |text| case.apply(text))
+    } else if let Some((before, after)) = config.insertion() {
+        replace_matches_with(line, matcher, limit, |text| format!("{}{}{}", before, text, after))
+    } else if config.preserve_case {
+        replace_expanding(line, matcher, config.replacement(), limit, |new, matched| match_case(&new, matched))
+    } else {
+        replace_all_matches(line, matcher, config.replacement(), limit)
+    }
+}
+
+/// Asks about each match span of `line` in turn and returns the ones to
+/// replace, stopping once `limit` have been chosen.
+fn choose_occurrences(
+    line: &str,
+    spans: &[(usize, usize)],
+    limit: Option<usize>,
+    color: &str,
+) -> io::Result<Vec<(usize, usize)>> {
+    let mut chosen = Vec::new();
+    for (k, &(start, end)) in (1..).zip(spans) {
+        if limit.is_some_and(|n| chosen.len() >= n) {
+            break;
+        }
+        let question = format!(
+            "Replace occurrence {} of {}? (y/n): {}{}{}\x1b[0m{} ",
+            k,
+            spans.len(),
+            &line[..start],
+            color,
+            &line[start..end],
+            &line[end..]
+        );
+        if prompt(&question)? == "y" {
+            chosen.push((start, end));
+        }
+    }
+    Ok(chosen)
+}
+
+/// Replaces only the given match spans of `line`, as [`replace_line`] would
+/// replace each of them.
+fn replace_chosen(line: &str, spans: &[(usize, usize)], matcher: &Matcher, config: &Config) -> String {
+    let mut result = String::new();
+    let mut last = 0;
+    for &(start, end) in spans {
+        result.push_str(&line[last..start]);
+        let matched = &line[start..end];
+        if let Some(case) = config.replace_case {
+            result.push_str(&case.apply(matched));
+        } else if let Some((before, after)) = config.insertion() {
+            result.push_str(&format!("{}{}{}", before, matched, after));
+        } else {
+            let new = match matcher.regex().and_then(|regex| regex.captures_at(line, start)) {
+                Some(captures) => {
+                    let mut new = String::new();
+                    captures.expand(&ampersand_to_group(config.replacement()), &mut new);
+                    new
+                }
+                None => expand_ampersand(config.replacement(), matched),
+            };
+            result.push_str(&if config.preserve_case { match_case(&new, matched) } else { new });
+        }
+        last = end;
+    }
+    result.push_str(&line[last..]);
+    result
+}
+
+/// Formats the changes from `old` to `new` as a unified diff (like `diff -u`)
+/// that `patch -p1` or `git apply` can apply. Both sides have the same number
+/// of lines, since replacements never add or remove lines. Returns an empty
+/// string if nothing changed.
+fn unified_diff(file_path: &str, old: &[String], new: &[String], missing_newline: bool) -> String {
+    const CONTEXT: usize = 3;
+    // Line ranges of the hunks; changes close enough to share context share a hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for i in (0..old.len()).filter(|&i| old[i] != new[i]) {
+        let start = i.saturating_sub(CONTEXT);
+        let end = usize::min(i + 1 + CONTEXT, old.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+    let path = file_path.trim_start_matches("./");
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", path, path);
+    let mut push_line = |prefix: char, text: &str, index: usize| {
+        diff.push_str(&format!("{}{}\n", prefix, text));
+        if missing_newline && index == old.len() - 1 {
+            diff.push_str("\\ No newline at end of file\n");
+        }
+    };
+    for (start, end) in hunks {
+        push_line('@', &format!("@ -{0},{1} +{0},{1} @@", start + 1, end - start), usize::MAX);
+        let mut i = start;
+        while i < end {
+            if old[i] == new[i] {
+                push_line(' ', &old[i], i);
+                i += 1;
+                continue;
+            }
+            // A run of changed lines is shown as all removals, then all additions
+            let run_end = (i..end).find(|&j| old[j] == new[j]).unwrap_or(end);
+            for (j, line) in (i..run_end).zip(&old[i..run_end]) {
+                push_line('-', line, j);
+            }
+            for (j, line) in (i..run_end).zip(&new[i..run_end]) {
+                push_line('+', line, j);
+            }
+            i = run_end;
+        }
+    }
+    diff
+}
+
+/// Writes replaced text back to the file (or `--output`), after a last
+/// confirmation unless `--yes` was given. Returns true if it was written.
+///
+/// # Errors
+/// Besides I/O errors, fails without writing anything if the file no longer
+/// holds `original`, the contents the replacements were made from, because
+/// something else changed it in the meantime.
+fn save_replacements(
+    text: &str,
+    original: &str,
+    changes: usize,
+    config: &Config,
+    file_path: &str,
+    out: &mut dyn Write,
+) -> Result<bool, Box<dyn Error>> {
+    let target = config.output.as_deref().map_or_else(|| file_path.to_string(), expand_path);
+    let target = target.as_str();
+    if changes == 0 {
+        writeln!(out, "No replacements made.")?;
+        return Ok(false);
+    }
+    // Prompts go straight to stdout, so anything still buffered must come first
+    out.flush()?;
+    if !config.yes && prompt(&format!("Write {} changes to {}? (y/n): ", changes, target))? != "y" {
+        writeln!(out, "Changes discarded. No replacements made.")?;
+        return Ok(false);
+    }
+    // Comparing contents rather than mtime also catches changes within the
+    // timestamp's resolution
+    if config.output.is_none() && Source::File(PathBuf::from(file_path)).read(config)? != original {
+        return Err(format!("{} changed on disk since it was read; not overwriting it. No replacements made.", file_path).into());
+    }
+    if config.backup && config.output.is_none() {
+        let backup = backup_path(file_path, config.backup_dir.as_deref().map(expand_path).as_deref());
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(file_path, &backup)?;
+    }
+    write_contents(target, text, config.encoding, file_path)?;
+    if config.output.is_some() {
+        writeln!(out, "Replacements made and saved to {}.", target)?;
+    } else {
+        writeln!(out, "Replacements made and file saved.")?;
+    }
+    Ok(true)
+}
+
+/// Formats one `--replace-log` entry: a timestamped `file:line` header
+/// followed by the old lines (`-`) and the new ones (`+`).
+fn log_entry(file_path: &str, line_num: usize, old: &[String], new: &[String]) -> String {
+    let mut entry = format!("{} {}:{}\n", humantime::format_rfc3339_seconds(SystemTime::now()), file_path, line_num);
+    for line in old {
+        entry.push_str(&format!("- {}\n", line));
+    }
+    for line in new {
+        entry.push_str(&format!("+ {}\n", line));
+    }
+    entry
+}
+
+/// Formats one `--replace-report` record as `path:line:old:new`.
+///
+/// A backslash, colon or newline inside the path or text is escaped as `\\`,
+/// `\:` or `\n`, so every record is one line with exactly three unescaped colons.
+fn report_record(file_path: &str, line_num: usize, old: &str, new: &str) -> String {
+    let escape = |field: &str| field.replace('\\', "\\\\").replace(':', "\\:").replace('\n', "\\n");
+    format!("{}:{}:{}:{}", escape(file_path), line_num, escape(old), escape(new))
+}
+
+/// Appends entries to the `--replace-log` file, creating it if needed.
+fn append_replace_log(path: &str, entries: &[String]) -> io::Result<()> {
+    let mut log = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    log.write_all(entries.concat().as_bytes())
+}
+
+/// Returns where `--backup` keeps the original of `file_path`: next to it with a
+/// `.bak` suffix, or under `backup_dir` at the same relative path.
+fn backup_path(file_path: &str, backup_dir: Option<&str>) -> PathBuf {
+    match backup_dir {
+        // Only the normal components are kept, so absolute paths and `..` stay inside the directory
+        Some(dir) => Path::new(dir).join(
+            Path::new(file_path)
+                .components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .collect::<PathBuf>(),
+        ),
+        None => PathBuf::from(format!("{}.bak", file_path)),
+    }
+}
+
+/// Searches (and with `--replace`, rewrites) `contents` as a whole for `--multiline`.
+///
+/// Each match is shown as the block of lines it spans, with the matched part
+/// highlighted; `--context` does not apply. Replacement is done in one pass
+/// over the whole text, so there are no per-line prompts, only the final
+/// confirmation before saving.
+fn search_multiline(
+    contents: &str,
+    regex: &Regex,
+    config: &Config,
+    file_path: &str,
+    out: &mut dyn Write,
+) -> Result<SearchStats, Box<dyn Error>> {
+    let limit = config.replace_count.unwrap_or(usize::MAX);
+    let spans: Vec<(usize, usize)> = regex.find_iter(contents).map(|m| (m.start(), m.end())).collect();
+    let stats = SearchStats { matches: spans.len(), lines_scanned: contents.lines().count() };
+    if config.count {
+        writeln!(out, "{}", spans.len())?;
+        return Ok(stats);
+    }
+    if config.files_with_matches || config.files_without_match {
+        if spans.is_empty() == config.files_without_match {
+            let name = config.url.as_deref().unwrap_or(file_path);
+            let terminator = if config.print0 { '\0' } else { '\n' };
+            write!(out, "{}{}", name, terminator)?;
+        }
+        return Ok(stats);
+    }
+
+    if !config.null {
+        writeln!(out, "Preview of matches:")?;
+    }
+    for (index, &(start, end)) in spans.iter().enumerate() {
+        if index > 0 {
+            if let Some(separator) = config.context_separator() {
+                writeln!(out, "{}", separator)?;
+            }
+        }
+        let (first_line, lines) = match_block(contents, start, end);
+        let mut offset = lines.start;
+        let mut block = Vec::new();
+        for (line_num, line) in (first_line..).zip(contents[lines].split('\n')) {
+            // Highlight the part of this line that falls inside the match
+            let (from, to) = (start.clamp(offset, offset + line.len()), end.clamp(offset, offset + line.len()));
+            let text = format!(
+                "{}{}{}\x1b[0m{}",
+                &line[..from - offset],
+                config.match_color(),
+                &line[from - offset..to - offset],
+                &line[to - offset..],
+            );
+            let text = expand_tabs(trim_line_ending(&text), config.tab_width);
+            block.push(format!("{}{}\x1b[0m: {}", LINE_NUMBER_COLOR, line_num, text));
+            offset += line.len() + 1;
+        }
+        // The whole match is one record, so with --null only its end gets a NUL
+        write!(out, "{}{}", block.join("\n"), config.line_terminator())?;
+    }
+
+    if config.replace {
+        if config.url.is_some() && config.output.is_none() {
+            writeln!(out, "Warning: --replace on a URL needs --output <path> to save the result. No changes will be made.")?;
+            return Ok(stats);
+        }
+        let changes = spans.len().min(limit);
+        let (text, replaced) = replace_multiline(contents, regex, config, changes);
+        if save_replacements(&text, contents, changes, config, file_path, out)? {
+            if let Some(log_path) = &config.replace_log {
+                let entry = format!("{} {}: {} multiline replacements\n", humantime::format_rfc3339_seconds(SystemTime::now()), file_path, changes);
+                append_replace_log(&expand_path(log_path), &[entry])?;
+            }
+            if config.replace_report {
+                for (line_num, old, new) in &replaced {
+                    writeln!(out, "{}", report_record(file_path, *line_num, old, new))?;
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Returns the 1-based number of the first line a `--multiline` match from
+/// `start` to `end` touches, and the byte range of the whole lines it spans
+/// (without the final line ending).
+fn match_block(contents: &str, start: usize, end: usize) -> (usize, Range<usize>) {
+    let block_start = contents[..start].rfind('\n').map_or(0, |i| i + 1);
+    let block_end = contents[end..].find('\n').map_or(contents.len(), |i| end + i);
+    let first_line = contents[..block_start].matches('\n').count() + 1;
+    (first_line, block_start..block_end)
+}
+
+/// Replaces the first `limit` matches of `regex` in the whole of `contents`,
+/// for `--multiline`. Returns the new text and each replaced match as
+/// (line number, old text, new text), for `--replace-report`.
+fn replace_multiline(
+    contents: &str,
+    regex: &Regex,
+    config: &Config,
+    limit: usize,
+) -> (String, Vec<(usize, String, String)>) {
+    let mut replaced = Vec::new();
+    let text = regex.replacen(contents, limit, |captures: &regex::Captures| {
+        let matched = captures.get(0).map_or("", |m| m.as_str());
+        let new = match (config.replace_case, config.insertion()) {
+            (Some(case), _) => case.apply(matched),
+            (None, Some((before, after))) => format!("{}{}{}", before, matched, after),
+            (None, None) => {
+                let mut new = String::new();
+                captures.expand(&ampersand_to_group(config.replacement()), &mut new);
+                if config.preserve_case { match_case(&new, matched) } else { new }
+            }
+        };
+        let start = captures.get(0).map_or(0, |m| m.start());
+        replaced.push((contents[..start].matches('\n').count() + 1, matched.to_string(), new.clone()));
+        new
+    });
+    (text.into_owned(), replaced)
+}
+
+/// Applies the configured replacement to all of `contents` when filtering
+/// standard input (see [`Config::is_stdin_filter`]). Returns the new text,
+/// with every line ending kept as it was, and how many lines (or `--multiline`
+/// matches) were replaced.
+fn filter_replace(contents: &str, matcher: &Matcher, config: &Config) -> (String, usize) {
+    if let (true, Matcher::Regex(regex)) = (config.multiline, matcher) {
+        let (text, replaced) = replace_multiline(contents, regex, config, config.replace_count.unwrap_or(usize::MAX));
+        return (text, replaced.len());
+    }
+    let config = config.for_line_count(contents.lines().count());
+    let matcher = config.replace_matcher(matcher);
+    let mut remaining = config.replace_count;
+    let mut changed = 0;
+    let mut text = String::with_capacity(contents.len());
+    for (i, chunk) in contents.split_inclusive('\n').enumerate() {
+        let line = trim_line_ending(chunk.strip_suffix('\n').unwrap_or(chunk));
+        if remaining == Some(0) || !config.in_range(i) || !matcher.is_match(line) {
+            text.push_str(chunk);
+            continue;
+        }
+        let (new_line, replaced) = replace_line(line, &matcher, &config, remaining);
+        remaining = remaining.map(|n| n - replaced);
+        changed += 1;
+        text.push_str(&new_line);
+        text.push_str(&chunk[line.len()..]);
+    }
+    (text, changed)
+}
+
+/// Writes one numbered line of output, with the matches of `highlight` highlighted
+/// and `note` (from `--show-function`) after it as a comment.
+///
+/// Applies `--trim`, `--max-columns` and `--tab-width`, and syntax highlighting for `file_path`.
+fn write_line(
+    out: &mut dyn Write,
+    line_num: usize,
+    line: &str,
+    highlight: Option<&Matcher>,
+    note: Option<&str>,
+    config: &Config,
+    file_path: &str,
+) -> io::Result<()> {
+    let line = if config.trim { line.trim_end() } else { line };
+    let (line, omitted) = truncate_columns(line, config.max_columns);
+    let mut marker = if omitted > 0 { format!(" [... {} chars omitted]", omitted) } else { String::new() };
+    if let Some(note) = note {
+        marker.push_str(&format!("  {}// {}\x1b[0m", NOTE_COLOR, note));
+    }
+    let line_num = format!("{}{}\x1b[0m", LINE_NUMBER_COLOR, line_num);
+    // Without color the line is written as is, so there are no escapes to strip
+    let line = if config.use_color() {
+        let spans = match highlight {
+            Some(matcher) => matcher.find_all(line).into_iter().take(config.max_matches_per_line()).collect(),
+            None => Vec::new(),
+        };
+        Cow::Owned(highlight_line(line, &spans, &config.match_color(), file_path, &config.ext_map))
+    } else {
+        Cow::Borrowed(line)
+    };
+    let line = expand_tabs(&line, config.tab_width);
+    write!(out, "{}: {}{}{}", line_num, line, marker, config.line_terminator())
+}
+
+/// A writer that drops ANSI escape sequences before passing bytes on.
+///
+/// Escape sequences may be split across `write` calls, so the parser state is kept
+/// between them.
+struct AnsiStripper<W: Write> {
+    inner: W,
+    state: EscapeState,
+}
+
+/// Where an `AnsiStripper` is within an escape sequence.
+#[derive(Clone, Copy, PartialEq)]
+enum EscapeState {
+    Text,
+    /// Just saw `ESC`.
+    Escape,
+    /// Inside a `ESC [` control sequence, waiting for its final byte.
+    Csi,
+}
+
+impl<W: Write> AnsiStripper<W> {
+    fn new(inner: W) -> AnsiStripper<W> {
+        AnsiStripper { inner, state: EscapeState::Text }
+    }
+}
+
+impl<W: Write> Write for AnsiStripper<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut text = Vec::with_capacity(buf.len());
+        for &byte in buf {
+            self.state = match (self.state, byte) {
+                (EscapeState::Text, 0x1b) => EscapeState::Escape,
+                (EscapeState::Text, _) => {
+                    text.push(byte);
+                    EscapeState::Text
+                }
+                (EscapeState::Escape, b'[') => EscapeState::Csi,
+                // A second ESC starts a new sequence rather than ending up as text.
+                (EscapeState::Escape, 0x1b) => EscapeState::Escape,
+                // Two-byte escapes end right after ESC.
+                (EscapeState::Escape, _) => EscapeState::Text,
+                (EscapeState::Csi, 0x40..=0x7e) => EscapeState::Text,
+                (EscapeState::Csi, _) => EscapeState::Csi,
+            };
+        }
+        self.inner.write_all(&text)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Runs the `--pre` command on `path` and returns what it wrote to stdout.
+///
+/// The command may include arguments (e.g. `unzip -p`); the path is passed
+/// as the last one. The command's stderr is passed through.
+fn run_preprocessor(command: &str, path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().ok_or("--pre command is empty")?;
+    let output = Command::new(program)
+        .args(parts)
+        .arg(path)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|err| format!("could not run --pre command `{}`: {}", command, err))?;
+    if !output.status.success() {
+        return Err(format!("--pre command `{}` exited with {}", command, output.status).into());
+    }
+    Ok(output.stdout)
+}
+
+/// Returns the editor to run for `--replace-interactive`: `$VISUAL`, then
+/// `$EDITOR`, then `vi`.
+fn editor_command() -> String {
+    env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string())
+}
+
+/// Opens `text` in `editor` via a temporary file and returns what was saved.
+///
+/// The editor command may include arguments (e.g. `code --wait`). The temporary
+/// file keeps the extension of `file_path` so editors can pick a syntax.
+fn edit_in_editor(text: &str, editor: &str, file_path: &str) -> io::Result<String> {
+    let ext = Path::new(file_path).extension().and_then(|e| e.to_str()).unwrap_or("txt");
+    let temp = env::temp_dir().join(format!("looneygrep-edit-{}.{}", std::process::id(), ext));
+    fs::write(&temp, format!("{}\n", text))?;
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = Command::new(program).args(parts).arg(&temp).status();
+    let edited = fs::read_to_string(&temp);
+    let _ = fs::remove_file(&temp);
+    let status = status?;
+    if !status.success() {
+        return Err(io::Error::other(format!("editor `{}` exited with {}", editor, status)));
+    }
+    edited
+}
+
+/// Prints a prompt and returns the user's trimmed answer from stdin.
+fn prompt(message: &str) -> io::Result<String> {
+    print!("{}", message);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+/// Decides whether a line matches the query and where.
+///
+/// A `Matcher` is built once per search (see [`Matcher::from_config`]) and shared
+/// by matching, highlighting and replacement, so case handling lives in one place.
+/// With `ignore_case`, the stored query is already lowercased.
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// Substring search using `str::find`.
+    Substring { query: String, ignore_case: bool },
+    /// SIMD-accelerated substring search using `memchr::memmem`.
+    Memmem { finder: Box<Finder<'static>>, ignore_case: bool },
+    /// The whole line (minus its line terminator) must equal the query.
+    WholeLine { query: String, ignore_case: bool },
+    /// The line (minus its line terminator) must begin or end with the query.
+    Anchored { query: String, ignore_case: bool, anchor: Anchor },
+    /// Regular expression search. Case, whole-line and whole-word options are compiled in.
+    Regex(Regex),
+    /// Matches of the inner matcher that form whole words.
+    Word(Box<Matcher>),
+    /// Matches of the inner matcher with the given text (compared exactly) right
+    /// before and/or after them.
+    Surrounded { inner: Box<Matcher>, preceded_by: Option<String>, followed_by: Option<String> },
+}
+
+impl Matcher {
+    /// Creates the default literal matcher for `query`.
+    pub fn new(query: &str, ignore_case: bool) -> Matcher {
+        let query = fold_case(query, ignore_case);
+        Matcher::Memmem { finder: Box::new(Finder::new(query.as_bytes()).into_owned()), ignore_case }
+    }
+
+    /// Creates a matcher that uses plain `str::find` scanning.
+    pub fn substring(query: &str, ignore_case: bool) -> Matcher {
+        Matcher::Substring { query: fold_case(query, ignore_case).into_owned(), ignore_case }
+    }
+
+    /// Creates a matcher that only accepts lines equal to `query`.
+    pub fn whole_line(query: &str, ignore_case: bool) -> Matcher {
+        Matcher::WholeLine { query: fold_case(query, ignore_case).into_owned(), ignore_case }
+    }
+
+    /// Creates a matcher that only accepts lines beginning or ending with `query`.
+    pub fn anchored(query: &str, ignore_case: bool, anchor: Anchor) -> Matcher {
+        Matcher::Anchored { query: fold_case(query, ignore_case).into_owned(), ignore_case, anchor }
+    }
+
+    /// Creates the matcher described by the search options in `config`.
+    ///
+    /// # Errors
+    /// Returns an error if `config.regex` is set and the query is not a valid regex.
+    pub fn from_config(config: &Config) -> Result<Matcher, regex::Error> {
+        if config.regex {
+            Ok(Matcher::Regex(build_regex(config)?))
+        } else if config.line_regexp {
+            Ok(Matcher::whole_line(&config.query, config.ignore_case))
+        } else if config.starts_with || config.ends_with {
+            let anchor = if config.starts_with { Anchor::Start } else { Anchor::End };
+            let matcher = Matcher::anchored(&config.query, config.ignore_case, anchor);
+            if config.word_regexp {
+                Ok(Matcher::Word(Box::new(matcher)))
+            } else {
+                Ok(matcher)
+            }
+        } else if config.word_regexp {
+            Ok(Matcher::Word(Box::new(Matcher::new(&config.query, config.ignore_case))))
+        } else {
+            Ok(Matcher::new(&config.query, config.ignore_case))
+        }
+    }
+
+    /// Returns the regex doing the matching, for expanding capture references.
+    fn regex(&self) -> Option<&Regex> {
+        match self {
+            Matcher::Regex(regex) => Some(regex),
+            Matcher::Surrounded { inner, .. } => inner.regex(),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the line matches. An empty query matches nothing.
+    pub fn is_match(&self, line: &str) -> bool {
+        match self {
+            Matcher::Substring { query, ignore_case } => {
+                !query.is_empty() && fold_case(line, *ignore_case).contains(query.as_str())
+            }
+            Matcher::Memmem { finder, ignore_case } => {
+                !finder.needle().is_empty()
+                    && finder.find(fold_case(line, *ignore_case).as_bytes()).is_some()
+            }
+            Matcher::WholeLine { query, ignore_case } => {
+                !query.is_empty() && fold_case(trim_line_ending(line), *ignore_case) == query.as_str()
+            }
+            Matcher::Anchored { .. } | Matcher::Word(_) | Matcher::Surrounded { .. } => !self.find_all(line).is_empty(),
+            Matcher::Regex(regex) => regex.is_match(line),
+        }
+    }
+
+    /// Returns the byte ranges `(start, end)` of all non-overlapping matches in the line.
+    pub fn find_all(&self, line: &str) -> Vec<(usize, usize)> {
+        match self {
+            Matcher::Substring { query, ignore_case } => {
+                if query.is_empty() {
+                    return Vec::new();
+                }
+                find_folded(line, *ignore_case, |haystack| {
+                    haystack
+                        .match_indices(query.as_str())
+                        .map(|(start, m)| (start, start + m.len()))
+                        .collect()
+                })
+            }
+            Matcher::Memmem { finder, ignore_case } => {
+                let len = finder.needle().len();
+                if len == 0 {
+                    return Vec::new();
+                }
+                find_folded(line, *ignore_case, |haystack| {
+                    finder.find_iter(haystack.as_bytes()).map(|start| (start, start + len)).collect()
+                })
+            }
+            Matcher::WholeLine { .. } => {
+                if self.is_match(line) {
+                    vec![(0, trim_line_ending(line).len())]
+                } else {
+                    Vec::new()
+                }
+            }
+            Matcher::Anchored { query, ignore_case, anchor } => {
+                if query.is_empty() {
+                    return Vec::new();
+                }
+                find_folded(line, *ignore_case, |haystack| {
+                    let haystack = trim_line_ending(haystack);
+                    match anchor {
+                        Anchor::Start if haystack.starts_with(query.as_str()) => vec![(0, query.len())],
+                        Anchor::End if haystack.ends_with(query.as_str()) => {
+                            vec![(haystack.len() - query.len(), haystack.len())]
+                        }
+                        _ => Vec::new(),
+                    }
+                })
+            }
+            Matcher::Regex(regex) => regex.find_iter(line).map(|m| (m.start(), m.end())).collect(),
+            Matcher::Word(inner) => inner
+                .find_all(line)
+                .into_iter()
+                .filter(|&(start, end)| is_whole_word(line, start, end))
+                .collect(),
+            Matcher::Surrounded { inner, preceded_by, followed_by } => inner
+                .find_all(line)
+                .into_iter()
+                .filter(|&(start, end)| {
+                    preceded_by.as_ref().is_none_or(|text| line[..start].ends_with(text.as_str()))
+                        && followed_by.as_ref().is_none_or(|text| line[end..].starts_with(text.as_str()))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Which end of the line an [`Matcher::Anchored`] query must sit at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    /// The line must begin with the query (`--starts-with`).
+    Start,
+    /// The line must end with the query (`--ends-with`).
+    End,
+}
+
+/// Compiles the search regex for `config.query`, anchoring it to the whole
+/// line for `line_regexp` or to word boundaries for `word_regexp`.
+///
+/// With `multiline`, `^` and `$` also match at the start and end of every line,
+/// since the regex then runs over a whole file at once. With `dotall`, `.`
+/// matches newlines too.
+fn build_regex(config: &Config) -> Result<Regex, regex::Error> {
+    let pattern = if config.line_regexp {
+        format!("^(?:{})\r?$", config.query)
+    } else if config.word_regexp {
+        format!(r"\b(?:{})\b", config.query)
+    } else {
+        config.query.clone()
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(config.ignore_case)
+        .multi_line(config.multiline)
+        .dot_matches_new_line(config.dotall)
+        .build()
+}
+
+/// Returns true if `c` can be part of a word for `--word-regexp`: a character
+/// of the regex crate's Unicode `\w`, so a literal query and `--regex` agree on
+/// where a word ends. That covers letters, digits, connector punctuation like
+/// `_`, and every combining mark (a decomposed `é`, a Devanagari virama).
+fn is_word_char(c: char) -> bool {
+    regex_syntax::is_word_character(c)
+}
+
+/// Returns true if the span `start..end` of `line` is not directly preceded or
+/// followed by a word character.
+fn is_whole_word(line: &str, start: usize, end: usize) -> bool {
+    !line[..start].chars().next_back().is_some_and(is_word_char)
+        && !line[end..].chars().next().is_some_and(is_word_char)
+}
+
+/// Parses a sed-style `s/pattern/replacement/flags` expression.
+///
+/// Any character may be used as the delimiter in place of `/`, and it can be
+/// escaped with a backslash inside the pattern or replacement. The only flags
+/// accepted are `g` (always implied) and `i` (ignore case).
+/// Returns the pattern, the replacement, and whether `i` was given.
+fn parse_substitution(expr: &str) -> Option<(String, String, bool)> {
+    let mut chars = expr.strip_prefix('s')?.chars();
+    let delimiter = chars.next().filter(|c| !c.is_alphanumeric() && *c != '\\')?;
+    let mut parts = vec![String::new()];
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(next) if next == delimiter => parts.last_mut()?.push(next),
+                Some(next) => {
+                    let part = parts.last_mut()?;
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => return None,
+            }
+        } else if c == delimiter {
+            parts.push(String::new());
+        } else {
+            parts.last_mut()?.push(c);
+        }
+    }
+    let [pattern, replacement, flags]: [String; 3] = parts.try_into().ok()?;
+    if pattern.is_empty() || !flags.chars().all(|f| f == 'g' || f == 'i') {
+        return None;
+    }
+    Some((pattern, replacement, flags.contains('i')))
+}
+
+/// Finds all non-overlapping matches of `query` in `line`.
+///
+/// Returns `(start, end)` byte ranges into the original `line`, even when
+/// `ignore_case` lowercasing changes the byte length of some characters.
+/// An empty query matches nothing.
+pub fn find_all_matches(line: &str, query: &str, ignore_case: bool) -> Vec<(usize, usize)> {
+    Matcher::substring(query, ignore_case).find_all(line)
+}
+
+/// Match highlighting and replacement, as used by the command-line tool.
+///
+/// These work on a single line with a literal query, matched exactly as
+/// [`find_all_matches`] does.
+pub mod text {
+    use super::Matcher;
+
+    /// Wraps every match of `query` in `line` with the ANSI escape `color` and a reset.
+    ///
+    /// # Example
+    /// ```rust
+    /// use looneygrep::text::highlight_all_matches;
+    /// let line = highlight_all_matches("Foo bar", "foo", true, "\x1b[31m");
+    /// assert_eq!(line, "\x1b[31mFoo\x1b[0m bar");
+    /// ```
+    pub fn highlight_all_matches(line: &str, query: &str, ignore_case: bool, color: &str) -> String {
+        super::highlight_all_matches(line, &Matcher::new(query, ignore_case), color, usize::MAX)
+    }
+
+    /// Replaces every match of `query` in `line` with `replacement`.
+    ///
+    /// The replacement is inserted literally and is not searched again.
+    ///
+    /// # Example
+    /// ```rust
+    /// use looneygrep::text::replace_all_matches;
+    /// assert_eq!(replace_all_matches("Foo foo", "foo", true, "bar"), "bar bar");
+    /// assert_eq!(replace_all_matches("Tom and Jerry", "and", false, "&"), "Tom & Jerry");
+    /// ```
+    pub fn replace_all_matches(line: &str, query: &str, ignore_case: bool, replacement: &str) -> String {
+        super::replace_matches_with(line, &Matcher::new(query, ignore_case), None, |_| replacement.to_string()).0
+    }
+
+    /// Like [`replace_all_matches`], but expands `&` in the replacement to the
+    /// matched text, as `--replace-with` does. `\&` gives a literal ampersand.
+    ///
+    /// # Example
+    /// ```rust
+    /// use looneygrep::text::replace_all_matches_expanding;
+    /// assert_eq!(replace_all_matches_expanding("Foo foo", "foo", true, "[&]"), "[Foo] [foo]");
+    /// assert_eq!(replace_all_matches_expanding("foo", "foo", false, "\\&"), "&");
+    /// ```
+    pub fn replace_all_matches_expanding(line: &str, query: &str, ignore_case: bool, replacement: &str) -> String {
+        super::replace_all_matches(line, &Matcher::new(query, ignore_case), replacement, None).0
+    }
+}
+
+/// Runs `find` over the case-folded line and maps the byte ranges it returns
+/// back onto the original line.
+///
+/// Without `ignore_case` the line is searched as is. Otherwise each range is
+/// widened to whole original characters, so it is always safe to slice with.
+fn find_folded(
+    line: &str,
+    ignore_case: bool,
+    find: impl FnOnce(&str) -> Vec<(usize, usize)>,
+) -> Vec<(usize, usize)> {
+    if !ignore_case {
+        return find(line);
+    }
+    // For every byte of the folded text, the range of the original character it came from.
+    let mut folded = String::with_capacity(line.len());
+    let mut origins = Vec::with_capacity(line.len());
+    for (start, c) in line.char_indices() {
+        let before = folded.len();
+        folded.extend(c.to_lowercase());
+        let origin = (start, start + c.len_utf8());
+        origins.extend(std::iter::repeat_n(origin, folded.len() - before));
+    }
+    find(&folded)
+        .into_iter()
+        .map(|(start, end)| (origins[start].0, origins[end - 1].1))
+        .collect()
+}
+
+/// Lowercases `text` character by character when `ignore_case` is set,
+/// borrowing it otherwise.
+fn fold_case(text: &str, ignore_case: bool) -> Cow<'_, str> {
+    if ignore_case {
+        Cow::Owned(text.chars().flat_map(char::to_lowercase).collect())
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Returns the line ending used by most lines in `contents`: `\r\n` or `\n`.
+fn line_ending(contents: &str) -> &'static str {
+    let newlines = memchr::memchr_iter(b'\n', contents.as_bytes()).count();
+    let crlf = memchr::memmem::find_iter(contents.as_bytes(), b"\r\n").count();
+    if crlf * 2 > newlines {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Strips a trailing `\r` or `\n` left on a line.
+fn trim_line_ending(line: &str) -> &str {
+    line.trim_end_matches(['\r', '\n'])
+}
+
+/// Cuts a line down to at most `max_columns` characters for display.
+///
+/// Returns the part to show and how many characters were left out.
+fn truncate_columns(line: &str, max_columns: Option<usize>) -> (&str, usize) {
+    let Some(max) = max_columns else {
+        return (line, 0);
+    };
+    match line.char_indices().nth(max) {
+        Some((cut, _)) => (&line[..cut], line[cut..].chars().count()),
+        None => (line, 0),
+    }
+}
+
+/// Expands tabs to spaces up to the next multiple of `tab_width` columns, for
+/// display. ANSI escape sequences (from highlighting) take up no columns.
+fn expand_tabs(line: &str, tab_width: Option<usize>) -> Cow<'_, str> {
+    let Some(width) = tab_width.filter(|_| line.contains('\t')) else {
+        return Cow::Borrowed(line);
+    };
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\t' => {
+                let spaces = width - column % width;
+                expanded.extend(std::iter::repeat_n(' ', spaces));
+                column += spaces;
+            }
+            '\x1b' => {
+                // Copy the escape through its final byte without counting it
+                expanded.push(c);
+                for c in chars.by_ref() {
+                    expanded.push(c);
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+            c => {
+                expanded.push(c);
+                column += 1;
+            }
+        }
+    }
+    Cow::Owned(expanded)
+}
+
+/// Highlights the first `max_matches` matches in a line using the given ANSI
+/// color escape.
+fn highlight_all_matches(line: &str, matcher: &Matcher, color: &str, max_matches: usize) -> String {
+    let mut result = String::new();
+    let mut last = 0;
+    for (start, end) in matcher.find_all(line).into_iter().take(max_matches) {
+        result.push_str(&line[last..start]);
+        result.push_str(color);
+        result.push_str(&line[start..end]);
+        result.push_str("\x1b[0m");
+        last = end;
+    }
+    result.push_str(&line[last..]);
+    result
+}
+
+/// Replaces matches in a line with `replacement`, at most `limit` of them
+/// (all when `None`), and returns the new line with the number replaced.
+///
+/// An empty query matches nothing, so the line is returned unchanged.
+/// Matches are found in the original line only, so a replacement that itself
+/// contains the query is never rescanned.
+///
+/// For regex matchers, `$1`, `$name` and `${name}` in the replacement expand to
+/// the matching capture groups.
+fn replace_all_matches(line: &str, matcher: &Matcher, replacement: &str, limit: Option<usize>) -> (String, usize) {
+    replace_expanding(line, matcher, replacement, limit, |new, _| new)
+}
+
+/// Like [`replace_all_matches`], but passes each expanded replacement and the
+/// text it replaces through `adjust` before it goes in.
+fn replace_expanding(
+    line: &str,
+    matcher: &Matcher,
+    replacement: &str,
+    limit: Option<usize>,
+    adjust: impl Fn(String, &str) -> String,
+) -> (String, usize) {
+    let limit = limit.unwrap_or(usize::MAX);
+    if let Some(regex) = matcher.regex() {
+        // Expand capture references for each match the matcher accepts
+        let replacement = ampersand_to_group(replacement);
+        let accepted = matcher.find_all(line);
+        let mut result = String::new();
+        let mut last = 0;
+        let mut replaced = 0;
+        for captures in regex.captures_iter(line) {
+            let whole = captures.get(0).expect("group 0 is the whole match");
+            if replaced == limit {
+                break;
+            }
+            if !accepted.contains(&(whole.start(), whole.end())) {
+                continue;
+            }
+            result.push_str(&line[last..whole.start()]);
+            let mut new = String::new();
+            captures.expand(&replacement, &mut new);
+            result.push_str(&adjust(new, whole.as_str()));
+            last = whole.end();
+            replaced += 1;
+        }
+        result.push_str(&line[last..]);
+        return (result, replaced);
+    }
+    replace_matches_with(line, matcher, Some(limit), |matched| adjust(expand_ampersand(replacement, matched), matched))
+}
+
+/// Gives `replacement` the case pattern of `matched` for `--preserve-case`:
+/// all upper (`COLOR` → `COLOUR`), capitalized (`Color` → `Colour`) or all
+/// lower. Text of any other case, or without letters, leaves it unchanged.
+fn match_case(replacement: &str, matched: &str) -> String {
+    let mut letters = matched.chars().filter(|c| c.is_alphabetic());
+    let Some(first) = letters.next() else {
+        return replacement.to_string();
+    };
+    let rest: Vec<char> = letters.collect();
+    if first.is_uppercase() && !rest.is_empty() && rest.iter().all(|c| c.is_uppercase()) {
+        replacement.to_uppercase()
+    } else if first.is_uppercase() && rest.iter().all(|c| c.is_lowercase()) {
+        let mut chars = replacement.chars();
+        chars.next().map_or_else(String::new, |c| c.to_uppercase().chain(chars).collect())
+    } else if first.is_lowercase() && rest.iter().all(|c| c.is_lowercase()) {
+        replacement.to_lowercase()
+    } else {
+        replacement.to_string()
+    }
+}
+
+/// Expands each `&` in `replacement` to the matched text, as in sed. `\&`
+/// gives a literal ampersand.
+fn expand_ampersand(replacement: &str, matched: &str) -> String {
+    if !replacement.contains('&') {
+        return replacement.to_string();
+    }
+    let mut expanded = String::with_capacity(replacement.len() + matched.len());
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'&') => expanded.push(chars.next().unwrap()),
+            '&' => expanded.push_str(matched),
+            c => expanded.push(c),
+        }
+    }
+    expanded
+}
+
+/// Rewrites `&` in a regex replacement to `${0}` (the whole match), and `\&`
+/// to a literal ampersand.
+fn ampersand_to_group(replacement: &str) -> Cow<'_, str> {
+    if !replacement.contains('&') {
+        return Cow::Borrowed(replacement);
+    }
+    Cow::Owned(expand_ampersand(replacement, "${0}"))
+}
+
+/// Like [`replace_all_matches`], but each match is replaced with `replace(matched_text)`.
+fn replace_matches_with(
+    line: &str,
+    matcher: &Matcher,
+    limit: Option<usize>,
+    replace: impl Fn(&str) -> String,
+) -> (String, usize) {
+    let mut result = String::new();
+    let mut last = 0;
+    let mut replaced = 0;
+    for (start, end) in matcher.find_all(line).into_iter().take(limit.unwrap_or(usize::MAX)) {
+        result.push_str(&line[last..start]);
+        result.push_str(&replace(&line[start..end]));
+        last = end;
+        replaced += 1;
+    }
+    result.push_str(&line[last..]);
+    (result, replaced)
+}
+
+/// Returns the arguments after the query that stand where a flag can: not the
+/// value of an option in [`VALUE_OPTIONS`], and not after `--`.
+fn flag_arguments(args: &[String]) -> Vec<&str> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        }
+        flags.push(arg.as_str());
+    }
+    flags
+}
+
+/// Parses a `--map-ext` value of the form `tsx=ts`. A leading dot on either
+/// side is ignored.
+fn parse_ext_mapping(mapping: &str) -> Option<(String, String)> {
+    let (from, to) = mapping.split_once('=')?;
+    let (from, to) = (from.trim().trim_start_matches('.'), to.trim().trim_start_matches('.'));
+    if from.is_empty() || to.is_empty() {
+        return None;
+    }
+    Some((from.to_string(), to.to_string()))
+}
+
+/// Parses a `--header` value of the form `Name: Value`.
+///
+/// Returns `None` if the name or value is not valid in an HTTP header.
+fn parse_header(header: &str) -> Option<(String, String)> {
+    let (name, value) = header.split_once(':')?;
+    let (name, value) = (name.trim(), value.trim());
+    HeaderName::from_bytes(name.as_bytes()).ok()?;
+    HeaderValue::from_str(value).ok()?;
+    Some((name.to_string(), value.to_string()))
+}
+
+/// Fetches the contents of a URL using a blocking HTTP request, sending
+/// `config.headers` and following at most `config.max_redirects` redirects.
+///
+/// If a redirect is not followed, a warning naming its target is printed and
+/// the body of the redirect response is searched.
+///
+/// A request that fails is retried up to `config.retries` times with
+/// exponential backoff, noting each failed attempt on stderr.
+///
+/// # Errors
+/// Returns an error if the request still fails after the last retry.
+fn fetch_url(url: &str, config: &Config) -> Result<String, Box<dyn Error>> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in &config.headers {
+        header_map.append(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+    }
+    let mut client = reqwest::blocking::Client::builder();
+    match config.max_redirects {
+        Some(0) => client = client.redirect(Policy::none()),
+        Some(max) => client = client.redirect(Policy::limited(max)),
+        None => {}
+    }
+    let client = client.build()?;
+    let mut attempt = 0;
+    let resp = loop {
+        match client.get(url).headers(header_map.clone()).send() {
+            Ok(resp) => break resp,
+            Err(err) if attempt < config.retries => {
+                let delay = retry_delay(attempt);
+                attempt += 1;
+                eprintln!(
+                    "looneygrep: {}: {} (retry {} of {} in {:.1}s)",
+                    url, err, attempt, config.retries, delay.as_secs_f64()
+                );
+                std::thread::sleep(delay);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+    if resp.status().is_redirection() {
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .unwrap_or("an unknown location");
+        eprintln!("Warning: {} redirected ({}) to {}, which was not followed.", url, resp.status(), location);
+    }
+    let body = resp.text()?;
+    Ok(body)
+}
+
+/// Returns how long to wait before retrying a request that has failed
+/// `attempt + 1` times: [`RETRY_DELAY`], doubled for each earlier retry.
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempt))
+}
+
+/// Returns why a matching file is left alone when replacing across directories
+/// (`"binary"` for a NUL byte, `"generated"` per [`looks_generated`]), or `None`
+/// if it will be replaced or `--force` was given.
+///
+/// `contents` should come from [`read_bulk_replace`], which keeps the NUL of a
+/// binary file that isn't valid UTF-8.
+fn bulk_replace_skip(path: &Path, contents: &str, config: &Config) -> Option<&'static str> {
+    if config.force {
+        None
+    } else if contents.contains('\0') {
+        Some("binary")
+    } else if looks_generated(path, contents) {
+        Some("generated")
+    } else {
+        None
+    }
+}
+
+/// Returns true if the first [`BINARY_SNIFF_LEN`] bytes of `path` contain a
+/// NUL. A file that can't be read is not called binary; reading it will fail.
+fn looks_binary(path: &Path) -> bool {
+    let mut start = Vec::new();
+    fs::File::open(path)
+        .and_then(|file| io::Read::read_to_end(&mut io::Read::take(file, BINARY_SNIFF_LEN), &mut start))
+        .is_ok_and(|_| start.contains(&0))
+}
+
+/// Reads a file when replacing across directories.
+///
+/// A binary file (per [`looks_binary`], checked on the raw bytes) is decoded
+/// lossily rather than failing as invalid UTF-8, since it will be skipped
+/// rather than rewritten. With `--force`, `--encoding` or `--pre` it is read
+/// as usual.
+fn read_bulk_replace(path: &Path, source: &Source, config: &Config) -> Result<String, Box<dyn Error>> {
+    if !config.force && config.encoding.is_none() && config.pre.is_none() && looks_binary(path) {
+        return Ok(String::from_utf8_lossy(&fs::read(path)?).into_owned());
+    }
+    source.read(config)
+}
+
+/// Returns true if a file looks minified or machine-generated: a `.min.` name
+/// (like `app.min.js`) or a line longer than [`GENERATED_LINE_LENGTH`].
+fn looks_generated(path: &Path, contents: &str) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().contains(".min."))
+        || contents.lines().any(|line| line.len() > GENERATED_LINE_LENGTH)
+}
+
+/// Prints a note about the file type based on its extension.
+fn print_file_type_note(file_path: &str, out: &mut dyn Write) -> io::Result<()> {
+    if let Some(ext) = std::path::Path::new(file_path).extension().and_then(|e| e.to_str()) {
+        match ext {
+            "rs" => writeln!(out, "(Rust source file detected)"),
+            "txt" => writeln!(out, "(Text file detected)"),
+            "md" => writeln!(out, "(Markdown file detected)"),
+            "html" | "htm" => writeln!(out, "(HTML file detected)"),
+            "css" => writeln!(out, "(CSS file detected)"),
+            "json" => writeln!(out, "(JSON file detected)"),
+            "xml" => writeln!(out, "(XML file detected)"),
+            "yaml" | "yml" => writeln!(out, "(YAML file detected)"),
+            "toml" => writeln!(out, "(TOML file detected)"),
+            "log" => writeln!(out, "(Log file detected)"),
+            "csv" => writeln!(out, "(CSV file detected)"),
+            "conf" | "cfg" => writeln!(out, "(Configuration file detected)"),
+            "sh" => writeln!(out, "(Shell script detected)"),
+            "bat" => writeln!(out, "(Batch script detected)"),
+            "php" => writeln!(out, "(PHP source file detected)"),
+            "java" => writeln!(out, "(Java source file detected)"),
+            "go" => writeln!(out, "(Go source file detected)"),
+            "py" => writeln!(out, "(Python source file detected)"),
+            "js" => writeln!(out, "(JavaScript source file detected)"),
+            "c" | "h" => writeln!(out, "(C source/header file detected)"),
+            _ => Ok(()),
+        }
+    } else {
+        Ok(())
+    }
+}
+
+/// Splits `line` into the styled ranges syntect gives it, based on the file
+/// extension.
+///
+/// An extension listed in `ext_map` (from `--map-ext`) is highlighted as the
+/// extension it maps to.
+fn syntax_highlight_ranges<'a>(line: &'a str, file_path: &str, ext_map: &[(String, String)]) -> Vec<(Style, &'a str)> {
+    // Loading the themes is slow, so it is done once per run
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    let ps = syntax_set();
+    let ts = THEMES.get_or_init(ThemeSet::load_defaults);
+    let syntax = find_syntax(file_path, ext_map);
+    let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+    let mut ranges = Vec::new();
+    for line in LinesWithEndings::from(line) {
+        ranges.extend(h.highlight_line(line, ps).unwrap());
+    }
+    ranges
+}
+
+/// Syntax-highlights `line` for `file_path` (see [`syntax_highlight_ranges`]),
+/// and draws the byte ranges in `spans` in the match `color` on top.
+///
+/// The matches are highlighted after syntect has styled the plain line, so the
+/// two sets of escapes never end up nested inside each other.
+fn highlight_line(line: &str, spans: &[(usize, usize)], color: &str, file_path: &str, ext_map: &[(String, String)]) -> String {
+    let styled = syntax_highlight_ranges(line, file_path, ext_map);
+    let mut highlighted = String::new();
+    let mut pos = 0;
+    for (style, text) in styled {
+        let end = pos + text.len();
+        // Cut the range wherever a match starts or ends inside it
+        while pos < end {
+            match spans.iter().find(|&&(start, stop)| start <= pos && pos < stop) {
+                Some(&(_, stop)) => {
+                    let cut = stop.min(end);
+                    highlighted.push_str(&format!("{}{}\x1b[0m", color, &line[pos..cut]));
+                    pos = cut;
+                }
+                None => {
+                    let next = spans.iter().map(|&(start, _)| start).filter(|&start| start > pos).min();
+                    let cut = next.map_or(end, |next| next.min(end));
+                    highlighted.push_str(&as_24_bit_terminal_escaped(&[(style, &line[pos..cut])], false));
+                    pos = cut;
+                }
+            }
+        }
+    }
+    highlighted
+}
+
+/// Returns the syntax definitions, loaded once per run because loading them is slow.
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// Returns the syntax for `file_path`'s extension, as remapped by `ext_map`,
+/// falling back to plain text.
+fn find_syntax(file_path: &str, ext_map: &[(String, String)]) -> &'static SyntaxReference {
+    let ps = syntax_set();
+    let ext = Path::new(file_path).extension().and_then(|e| e.to_str()).unwrap_or("");
+    let ext = ext_map.iter().find(|(from, _)| from == ext).map_or(ext, |(_, to)| to.as_str());
+    ps.find_syntax_by_extension(ext).unwrap_or_else(|| ps.find_syntax_plain_text())
+}
+
+/// Finds the definition enclosing each line for `--show-function`, as the
+/// 0-based line index and name of the nearest function, class, etc. around it.
+///
+/// Definitions are the names syntect scopes as one of [`DEFINITION_SCOPES`].
+/// What they enclose is guessed from indentation: a definition runs until the
+/// next non-blank line indented no deeper than it, except for lines starting
+/// with `)`, `{` or `where`, which continue a long signature.
+fn enclosing_definitions(lines: &[String], file_path: &str, ext_map: &[(String, String)]) -> Vec<Option<(usize, String)>> {
+    let ps = syntax_set();
+    let definition_scopes: Vec<Scope> = DEFINITION_SCOPES.iter().filter_map(|scope| Scope::new(scope).ok()).collect();
+    let mut state = ParseState::new(find_syntax(file_path, ext_map));
+    let mut stack = ScopeStack::new();
+    // Definitions still open, as (indentation, line index, name), innermost last
+    let mut open: Vec<(usize, usize, String)> = Vec::new();
+    let mut enclosing = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        // The syntaxes are loaded for lines that keep their newline
+        let text = format!("{}\n", line);
+        let ops = state.parse_line(&text, ps).unwrap_or_default();
+        let mut name = String::new();
+        for (region, op) in ScopeRegionIterator::new(&ops, &text) {
+            if stack.apply(op).is_err() {
+                break;
+            }
+            let in_name = stack.as_slice().iter().any(|scope| definition_scopes.iter().any(|d| d.is_prefix_of(*scope)));
+            if in_name {
+                name.push_str(region);
+            } else if !name.is_empty() {
+                // Only the first name on a line counts
+                break;
+            }
+        }
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        let continues_signature = trimmed.starts_with([')', '{']) || trimmed.starts_with("where");
+        if !trimmed.is_empty() && !continues_signature {
+            while open.last().is_some_and(|(def_indent, ..)| *def_indent >= indent) {
+                open.pop();
+            }
+        }
+        enclosing.push(open.last().map(|(_, def_idx, def_name)| (*def_idx, def_name.clone())));
+        let name = name.trim();
+        if !name.is_empty() {
+            open.push((indent, i, name.to_string()));
+        }
+    }
+    enclosing
+}
+
+/// Searches lines using a custom matcher closure.
+///
+/// # Arguments
+///
+/// * `lines` - An iterator over lines to search.
+/// * `matcher` - A closure that takes a line and returns `true` if it matches.
+///
+/// # Returns
+///
+/// A vector of matching lines.
+///
+/// # Example
+///
+/// ```rust
+/// use looneygrep::search;
+/// let lines = vec!["foo", "bar", "baz"];
+/// let matches = search(lines, |line| line.contains("ba"));
 /// assert_eq!(matches, vec!["bar", "baz"]);
 /// ```
 pub fn search<'a, I, F>(lines: I, matcher: F) -> Vec<&'a str>
@@ -396,70 +3699,1748 @@ where
     lines.into_iter().filter(|line| matcher(line)).collect()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Searches lines using a custom matcher closure, keeping line numbers.
+///
+/// # Arguments
+///
+/// * `lines` - An iterator over lines to search.
+/// * `matcher` - A closure that takes a line and returns `true` if it matches.
+///
+/// # Returns
+///
+/// A vector of `(line_number, line)` pairs for the matching lines. Line
+/// numbers start at 1, as in the command-line output.
+///
+/// # Example
+///
+/// ```rust
+/// use looneygrep::search_indexed;
+/// let lines = vec!["foo", "bar", "baz"];
+/// let matches = search_indexed(lines, |line| line.contains("ba"));
+/// assert_eq!(matches, vec![(2, "bar"), (3, "baz")]);
+/// ```
+pub fn search_indexed<'a, I, F>(lines: I, matcher: F) -> Vec<(usize, &'a str)>
+where
+    I: IntoIterator<Item = &'a str>,
+    F: Fn(&str) -> bool,
+{
+    (1..).zip(lines).filter(|(_, line)| matcher(line)).collect()
+}
+
+/// Lazily searches lines read from `reader` using a custom matcher closure.
+///
+/// Only one line is held in memory at a time, so this works on streams of
+/// any size.
+///
+/// # Arguments
+///
+/// * `reader` - Any buffered reader, such as a `BufReader<File>` or locked stdin.
+/// * `matcher` - A closure that takes a line and returns `true` if it matches.
+///
+/// # Returns
+///
+/// An iterator of `(line_number, line)` pairs for the matching lines, with
+/// line numbers starting at 1 and line endings removed. A read error is
+/// yielded as an `Err` item.
+///
+/// # Example
+///
+/// ```rust
+/// use looneygrep::search_reader;
+/// use std::io::Cursor;
+/// let reader = Cursor::new("foo\nbar\nbaz\n");
+/// let matches: Vec<(usize, String)> = search_reader(reader, |line| line.contains("ba"))
+///     .collect::<Result<_, _>>()
+///     .unwrap();
+/// assert_eq!(matches, vec![(2, "bar".to_string()), (3, "baz".to_string())]);
+/// ```
+pub fn search_reader<R, F>(reader: R, matcher: F) -> impl Iterator<Item = io::Result<(usize, String)>>
+where
+    R: BufRead,
+    F: Fn(&str) -> bool,
+{
+    (1..).zip(reader.lines()).filter_map(move |(line_num, line)| match line {
+        Ok(line) if matcher(&line) => Some(Ok((line_num, line))),
+        Ok(_) => None,
+        Err(err) => Some(Err(err)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replaces every match in `line`, returning only the new text.
+    fn replace_text(line: &str, matcher: &Matcher, replacement: &str) -> String {
+        replace_all_matches(line, matcher, replacement, None).0
+    }
+
+    /// Runs `search_contents` into a buffer and returns the match count and the
+    /// output with colors stripped.
+    fn render(contents: &str, config: &Config) -> (usize, String) {
+        let mut out = AnsiStripper::new(Vec::new());
+        let count = search_contents(contents, config, &config.file_path, &mut out).unwrap().matches;
+        (count, String::from_utf8(out.inner).unwrap())
+    }
+
+    /// Tests that context lines are correctly identified around matches.
+    #[test]
+    fn test_context_lines() {
+        let contents = "\
+line1
+match
+line3
+match
+line5";
+        let config = Config {
+            query: "match".to_string(),
+            file_path: "".to_string(),
+            ignore_case: false,
+            replace: false,
+            url: None,
+            context: 1,
+            search_all: false,
+            ..Default::default()
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        let matches: Vec<(usize, &str)> = lines.iter().enumerate()
+            .filter(|(_, line)| (*line).contains("match"))
+            .map(|(i, line)| (i, *line))
+            .collect();
+        assert_eq!(matches, vec![(1, "match"), (3, "match")]);
+
+        let (count, output) = render(contents, &config);
+        assert_eq!(count, 2);
+        assert_eq!(
+            output,
+            "Preview of matches:\n1: line1\n2: match\n3: line3\n4: match\n5: line5\n"
+        );
+    }
+
+    /// Tests that `--head` and `--tail` limit matching to the ends of each file,
+    /// within any `--range`.
+    #[test]
+    fn test_head_tail() {
+        let contents = "foo 1\nfoo 2\nbar\nfoo 4\nfoo 5";
+        let config = Config { query: "foo".to_string(), head: Some(2), ..Default::default() };
+        let (count, rendered) = render(contents, &config);
+        assert_eq!(count, 2);
+        assert!(rendered.starts_with("Preview of matches:\n1: foo 1\n2: foo 2\n"));
+        assert!(!rendered.contains("foo 4"));
+
+        let config = Config { head: None, tail: Some(3), ..config };
+        let (count, rendered) = render(contents, &config);
+        assert_eq!(count, 2);
+        assert!(rendered.starts_with("Preview of matches:\n4: foo 4\n5: foo 5\n"));
+        assert_eq!(render("foo", &Config { tail: Some(10), ..config.clone() }).0, 1);
+        assert_eq!(render(contents, &Config { line_range: Some((1, 4)), ..config.clone() }).0, 1);
+
+        let args = ["lg", "foo", "log.txt", "--head", "5", "--tail", "5"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--percent` adds the searched line count and matching share to `--count`.
+    #[test]
+    fn test_count_percent() {
+        let args = ["lg", "ERROR", "app.log", "--count", "--percent"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        let (count, rendered) = render("ERROR a\nok\nok\nERROR b\nok\nok\nok\nok", &config);
+        assert_eq!(count, 2);
+        assert_eq!(rendered, "2/8 (25.0%)\n");
+        assert_eq!(render("", &config).1, "0/0 (0.0%)\n");
+        let config = Config { line_range: Some((1, 3)), ..config };
+        assert_eq!(render("ERROR\nok\nok\nERROR", &config).1, "1/3 (33.3%)\n");
+
+        let args = ["lg", "ERROR", "app.log", "--percent"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that reading standard input with a replacement works as a filter
+    /// that writes out the whole replaced text.
+    #[test]
+    fn test_stdin_filter() {
+        let args = ["lg", "foo", "-", "--replace-with", "baz"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(config.is_stdin_filter());
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert_eq!(
+            filter_replace("foo bar\r\nnone\nfoo foo", &matcher, &config),
+            ("baz bar\r\nnone\nbaz baz".to_string(), 2)
+        );
+
+        let config = Config { replace_count: Some(1), ..config };
+        assert_eq!(filter_replace("foo\nfoo\n", &matcher, &config).0, "baz\nfoo\n");
+
+        for args in [&["lg", "foo", "-"][..], &["lg", "foo", "-", "notes.txt", "--replace-with", "baz"]] {
+            let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+            assert!(!config.is_stdin_filter());
+        }
+
+        for flags in [&["--count"][..], &["--replace", "--diff"], &["-l"], &["--highlight-only"], &["--output", "out.txt"]] {
+            let args = ["lg", "foo", "-", "--replace-with", "baz"].iter().chain(flags);
+            assert!(Config::build(args.map(|s| s.to_string())).is_err(), "{:?}", flags);
+        }
+    }
+
+    /// Tests that `--sort-matches` prints the lines with the most occurrences first.
+    #[test]
+    fn test_sort_matches() {
+        let config = Config { query: "ab".to_string(), sort_matches: true, ..Default::default() };
+        let (count, rendered) = render("ab\nab ab ab\nnone\nab ab\nab", &config);
+        assert_eq!(count, 4);
+        assert_eq!(rendered, "Preview of matches:\n2: ab ab ab\n4: ab ab\n1: ab\n5: ab\n");
+
+        let config = Config { context: 1, ..config };
+        let (_, rendered) = render("x\nab\nab ab\ny", &config);
+        assert_eq!(rendered, "Preview of matches:\n2: ab\n3: ab ab\n4: y\n---\n1: x\n2: ab\n3: ab ab\n");
+    }
+
+    /// Tests that `.looneygreprc` contents are parsed into defaults.
+    #[test]
+    fn test_rc_defaults_parse() {
+        let defaults = RcDefaults::parse("ignore-case = true\ncontext = 2\n").unwrap();
+        assert!(defaults.ignore_case);
+        assert_eq!(defaults.context, 2);
+        let empty = RcDefaults::parse("").unwrap();
+        assert!(!empty.ignore_case);
+        assert_eq!(empty.context, 0);
+        assert!(RcDefaults::parse("context = \"two\"").is_err());
+
+        let defaults = RcDefaults::parse("color = \"always\"\nword-regexp = true\nregex = true\n").unwrap();
+        assert_eq!(defaults.color, ColorChoice::Always);
+        assert!(defaults.word_regexp && defaults.regex);
+        assert!(RcDefaults::parse("color = \"sometimes\"").is_err());
+        let err = RcDefaults::parse("colour = \"always\"").unwrap_err();
+        assert!(err.message().contains("colour"), "{}", err.message());
+
+        // The first rc file found is used, and a bad one is reported with its path
+        let dir = env::temp_dir().join(format!("looneygrep-rc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (missing, rc) = (dir.join("missing"), dir.join(RC_FILE_NAME));
+        fs::write(&rc, "context = 3").unwrap();
+        assert_eq!(RcDefaults::load_from(&[missing.clone(), rc.clone()]).unwrap().context, 3);
+        fs::write(&rc, "colour = \"always\"").unwrap();
+        let err = RcDefaults::load_from(&[missing, rc.clone()]).unwrap_err();
+        assert!(err.contains(&rc.display().to_string()) && err.contains("colour"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that `--no-ignore-case`, `--no-word-regexp` and `--no-regex` undo
+    /// the options they negate, as set earlier or by `.looneygreprc`.
+    #[test]
+    fn test_negated_flags() {
+        let args = ["lg", "foo", "a.txt", "--ignore-case", "-w", "--regex", "--no-ignore-case", "--no-word-regexp", "--no-regex"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(!config.ignore_case && !config.word_regexp && !config.regex);
+    }
+
+    /// Tests that recursive collection handles symlinked directories and cycles.
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_files_symlinks() {
+        let root = env::temp_dir().join(format!("looneygrep-walk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/a.txt"), "foo").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+
+        let mut config = Config { recursive: true, ..Default::default() };
+        let mut files = Vec::new();
+        let mut visited = HashSet::from([fs::canonicalize(&root).unwrap()]);
+        collect_files(&root, 1, &config, &mut visited, &mut files).unwrap();
+        assert_eq!(files, vec![root.join("sub/a.txt")]);
+
+        config.follow_symlinks = true;
+        files.clear();
+        let mut visited = HashSet::from([fs::canonicalize(&root).unwrap()]);
+        collect_files(&root, 1, &config, &mut visited, &mut files).unwrap();
+        assert_eq!(files, vec![root.join("sub/a.txt")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that recursive collection stops at the maximum depth.
+    #[test]
+    fn test_collect_files_max_depth() {
+        let root = env::temp_dir().join(format!("looneygrep-depth-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("top.txt"), "foo").unwrap();
+        fs::write(root.join("a/mid.txt"), "foo").unwrap();
+        fs::write(root.join("a/b/deep.txt"), "foo").unwrap();
+
+        let collect = |max_depth| {
+            let config = Config { recursive: true, max_depth, ..Default::default() };
+            let mut files = Vec::new();
+            let mut visited = HashSet::new();
+            collect_files(&root, 1, &config, &mut visited, &mut files).unwrap();
+            files.len()
+        };
+        assert_eq!(collect(Some(1)), 1);
+        assert_eq!(collect(Some(2)), 2);
+        assert_eq!(collect(None), 3);
+        fs::remove_dir_all(&root).unwrap();
+
+        let build = |value: &str| Config::build(["lg", "foo", "-r", "--max-depth", value].iter().map(|s| s.to_string()));
+        assert_eq!(build("2").unwrap().max_depth, Some(2));
+        assert_eq!(build("abc").unwrap_err(), "Invalid --max-depth value");
+        assert!(build("-1").is_err());
+        assert_eq!(build("0").unwrap_err(), "--max-depth must be at least 1 (the starting directory only)");
+        assert!(Config::build(["lg", "foo", "--max-depth"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `-l` prints only matching file names, NUL-terminated with `--print0`.
+    #[test]
+    fn test_files_with_matches() {
+        let config = Config { query: "foo".to_string(), files_with_matches: true, ..Default::default() };
+        let mut out = Vec::new();
+        search_contents("foo\nbar", &config, "a b.txt", &mut out).unwrap();
+        search_contents("bar", &config, "c.txt", &mut out).unwrap();
+        assert_eq!(out, b"a b.txt\n");
+
+        let config = Config { print0: true, ..config };
+        let mut out = Vec::new();
+        search_contents("foo", &config, "a\nb.txt", &mut out).unwrap();
+        assert_eq!(out, b"a\nb.txt\0");
+
+        let args = ["lg", "foo", "notes.txt", "--print0"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `-L` prints only the names of files without a match.
+    #[test]
+    fn test_files_without_match() {
+        let config = Config { query: "License".to_string(), files_without_match: true, ..Default::default() };
+        let mut out = Vec::new();
+        search_contents("// License: MIT\nfn main() {}", &config, "a.rs", &mut out).unwrap();
+        search_contents("fn main() {}", &config, "b.rs", &mut out).unwrap();
+        assert_eq!(out, b"b.rs\n");
+
+        let args = ["lg", "foo", "--all", "-l", "-L"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--range` limits matching to a span of lines.
+    #[test]
+    fn test_line_range() {
+        assert_eq!(parse_line_range("100:200"), Some((100, 200)));
+        assert_eq!(parse_line_range("5:"), Some((5, usize::MAX)));
+        assert_eq!(parse_line_range(":7"), Some((1, 7)));
+        assert_eq!(parse_line_range("0:3"), None);
+        assert_eq!(parse_line_range("9:3"), None);
+        assert_eq!(parse_line_range("12"), None);
+
+        let config = Config { query: "foo".to_string(), line_range: Some((2, 3)), ..Default::default() };
+        let (count, rendered) = render("foo\nfoo\nbar\nfoo", &config);
+        assert_eq!(count, 1);
+        assert!(rendered.starts_with("Preview of matches:\n2: foo\n"));
+        assert!(!rendered.contains("---"));
+        let config = Config { count: true, ..config };
+        assert_eq!(render("foo\nfoo\nfoo\nfoo", &config).0, 2);
+    }
+
+    /// Tests that `--mark-file-edges` notes context clipped by the start or end of the file.
+    #[test]
+    fn test_mark_file_edges() {
+        let config = Config { query: "foo".to_string(), context: 2, mark_file_edges: true, ..Default::default() };
+        let (_, rendered) = render("foo\na\nb\nc\nd\ne\nfoo", &config);
+        assert!(rendered.starts_with(
+            "Preview of matches:\n(top of file)\n1: foo\n2: a\n3: b\n---\n5: d\n6: e\n7: foo\n(end of file)\n"
+        ));
+
+        let (_, rendered) = render("a\nb\nc\nfoo\nd\ne\nf", &config);
+        assert!(!rendered.contains("(top of file)") && !rendered.contains("(end of file)"));
+    }
+
+    /// Tests that context windows that overlap or touch print as one group, with
+    /// a separator only before a gap.
+    #[test]
+    fn test_context_groups_merge() {
+        let config = Config { query: "foo".to_string(), context: 1, ..Default::default() };
+        let (count, rendered) = render("foo\na\nb\nfoo\nfoo\nc\nd\ne\nfoo", &config);
+        assert_eq!(count, 4);
+        assert_eq!(
+            rendered,
+            "Preview of matches:\n1: foo\n2: a\n3: b\n4: foo\n5: foo\n6: c\n---\n8: e\n9: foo\n"
+        );
+
+        // A match shown as context of the one before it is still highlighted
+        let config = Config { color: ColorChoice::Always, ..config };
+        let mut out = Vec::new();
+        search_contents("foo\nfoo", &config, "", &mut out).unwrap();
+        let rendered = String::from_utf8(out).unwrap();
+        assert_eq!(rendered.matches(config.match_color().as_ref()).count(), 2);
+    }
+
+    /// Tests that the separator between match groups can be changed or removed.
+    #[test]
+    fn test_context_separator() {
+        let config = Config { query: "foo".to_string(), context_separator: Some("--".to_string()), ..Default::default() };
+        let (_, rendered) = render("foo\nbar\nfoo", &config);
+        assert!(rendered.starts_with("Preview of matches:\n1: foo\n--\n3: foo\n"));
+        assert_eq!(rendered.matches("--\n").count(), 1);
+
+        let config = Config { no_context_separator: true, ..config };
+        let (_, rendered) = render("foo\nbar\nfoo", &config);
+        assert!(rendered.starts_with("Preview of matches:\n1: foo\n3: foo\n"));
+    }
+
+    /// Tests that `--json-lines` prints one JSON object per matching line.
+    #[test]
+    fn test_json_lines() {
+        let config = Config { query: "foo".to_string(), output_format: OutputFormat::JsonLines, ..Default::default() };
+        let mut out = Vec::new();
+        let stats = search_contents("a\nfoo \"b\"\nfoo", &config, "src/x.rs", &mut out).unwrap();
+        assert_eq!(stats.matches, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"path\":\"src/x.rs\",\"line_number\":2,\"line\":\"foo \\\"b\\\"\"}\n\
+             {\"path\":\"src/x.rs\",\"line_number\":3,\"line\":\"foo\"}\n"
+        );
+    }
+
+    /// Tests that `--null` ends each printed line, or each multiline match, with a NUL byte.
+    #[test]
+    fn test_null_output() {
+        let config = Config { query: "foo".to_string(), null: true, ..Default::default() };
+        let (_, rendered) = render("foo\nbar\nfoo", &Config { file_path: "notes.txt".to_string(), ..config.clone() });
+        assert_eq!(rendered, "1: foo\x003: foo\0");
+
+        // Per-file headers are NUL-terminated records too
+        let root = env::temp_dir().join(format!("looneygrep-null-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "foo").unwrap();
+        fs::write(root.join("b.txt"), "foo").unwrap();
+        let paths = vec![root.join("a.txt").to_string_lossy().to_string(), root.join("b.txt").to_string_lossy().to_string()];
+        let mut out = Vec::new();
+        run_with_writer(Config { paths: paths.clone(), color: ColorChoice::Never, ..config }, &mut out).unwrap();
+        let records: Vec<String> = String::from_utf8(out).unwrap().split('\0').map(String::from).collect();
+        let header = |path: &str| format!("=== Searching in file: {} ===", path);
+        assert_eq!(records, [header(&paths[0]), "1: foo".to_string(), header(&paths[1]), "1: foo".to_string(), String::new()]);
+        fs::remove_dir_all(&root).unwrap();
+
+        let config = Config { query: "a\nb".to_string(), regex: true, multiline: true, null: true, ..Default::default() };
+        let (_, rendered) = render("a\nb\nc", &config);
+        assert!(rendered.starts_with("1: a\n2: b\0"), "{:?}", rendered);
+    }
+
+    /// Tests that the delay between `--retries` attempts doubles each time.
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), RETRY_DELAY);
+        assert_eq!(retry_delay(1), RETRY_DELAY * 2);
+        assert_eq!(retry_delay(3), RETRY_DELAY * 8);
+        assert!(retry_delay(u32::MAX) >= retry_delay(31));
+    }
+
+    /// Tests that `find_matches` returns each matching line with its spans,
+    /// and nothing for lines outside `--range`.
+    #[test]
+    fn test_find_matches() {
+        let path = env::temp_dir().join(format!("looneygrep-find-matches-{}.txt", std::process::id()));
+        fs::write(&path, "foo bar foo\r\nbaz\nfoo\n").unwrap();
+        let config = Config {
+            query: "foo".to_string(),
+            file_path: path.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let matches = find_matches(&config).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(
+            matches[0],
+            Match { path: path.clone(), line_number: 1, line: "foo bar foo".to_string(), spans: vec![0..3, 8..11] }
+        );
+        assert_eq!((matches[1].line_number, matches[1].line.as_str()), (3, "foo"));
+        assert_eq!(matches[1].spans.first(), Some(&(0..3)));
+
+        let config = Config { line_range: Some((2, 3)), ..config };
+        assert_eq!(find_matches(&config).unwrap().len(), 1);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(find_matches(&config), Err(LooneygrepError::UnreadableSources(1)));
+    }
+
+    /// Tests that `find_matches` finds the same matches `run_with_writer` counts,
+    /// including `--multiline` matches that span lines.
+    #[test]
+    fn test_find_matches_agrees_with_run() {
+        let path = env::temp_dir().join(format!("looneygrep-find-agree-{}.txt", std::process::id()));
+        fs::write(&path, "fn a() {\n    foo\n}\nfoo bar\nfood\n").unwrap();
+        let file_path = path.to_string_lossy().to_string();
+        let configs = [
+            Config { query: "foo".to_string(), ..Default::default() },
+            Config { query: "foo".to_string(), word_regexp: true, ..Default::default() },
+            Config { query: "foo".to_string(), line_range: Some((0, 2)), ..Default::default() },
+            Config { query: r"\{\n\s*foo".to_string(), regex: true, multiline: true, ..Default::default() },
+            Config { query: r"\{.*\}".to_string(), regex: true, multiline: true, dotall: true, ..Default::default() },
+        ];
+        for config in configs {
+            let config = Config { file_path: file_path.clone(), ..config };
+            let found = find_matches(&config).unwrap();
+            let counted = run_with_writer(config.clone(), &mut Vec::new()).unwrap();
+            assert_eq!(found.len(), counted, "{:?}", config.query);
+        }
+
+        let config = Config { query: r"\{.*\}".to_string(), regex: true, multiline: true, dotall: true, file_path: file_path.clone(), ..Default::default() };
+        let found = find_matches(&config).unwrap();
+        assert_eq!((found[0].line_number, found[0].line.as_str()), (1, "fn a() {\n    foo\n}"));
+        assert_eq!(found[0].spans.first(), Some(&(7..18)));
+        let config = Config { name_only: true, ..config };
+        assert!(matches!(find_matches(&config), Err(LooneygrepError::InvalidConfig(_))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that `--show-function` notes the definition enclosing each match.
+    #[test]
+    fn test_enclosing_definitions() {
+        let contents = "struct A;\n\nimpl A {\n    fn new(\n        x: u8,\n    ) -> A {\n        A\n    }\n}\n\nfn main() {}\nlet x;";
+        let lines: Vec<String> = contents.lines().map(String::from).collect();
+        let enclosing = enclosing_definitions(&lines, "lib.rs", &[]);
+        let names: Vec<Option<&str>> = enclosing.iter().map(|e| e.as_ref().map(|(_, name)| name.as_str())).collect();
+        assert_eq!(
+            names,
+            [None, Some("A"), None, Some("A"), Some("new"), Some("new"), Some("new"), Some("A"), None, None, None, None]
+        );
+        assert_eq!(enclosing[6], Some((3, "new".to_string())));
+
+        let config = Config { query: "A$".to_string(), regex: true, show_function: true, file_path: "lib.rs".to_string(), ..Default::default() };
+        let (_, rendered) = render(contents, &config);
+        let line = rendered.lines().find(|line| line.starts_with("7: ")).unwrap();
+        assert!(line.ends_with("  // in new (line 4)"), "{:?}", line);
+        assert_eq!(rendered.matches("// in").count(), 1);
+    }
+
+    /// Tests that `--tab-width` expands tabs to tab stops, ignoring color escapes.
+    #[test]
+    fn test_expand_tabs() {
+        assert_eq!(expand_tabs("a\tbc\td", Some(4)), "a   bc  d");
+        assert_eq!(expand_tabs("\x1b[31mab\x1b[0m\tc", Some(4)), "\x1b[31mab\x1b[0m  c");
+        assert_eq!(expand_tabs("a\tb", None), "a\tb");
+
+        let config = Config { query: "b".to_string(), tab_width: Some(2), ..Default::default() };
+        let (_, rendered) = render("\tb", &config);
+        assert!(rendered.contains("1:   b\n"), "{:?}", rendered);
+        let args = ["lg", "foo", "notes.txt", "--tab-width", "0"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--highlight-only` prints every line, highlighting only the matches.
+    #[test]
+    fn test_highlight_only() {
+        let config = Config { query: "foo".to_string(), highlight_only: true, ..Default::default() };
+        let (count, rendered) = render("a\nfoo b\nc", &config);
+        assert_eq!(count, 1);
+        assert_eq!(rendered, "1: a\n2: foo b\n3: c\n");
+
+        let config = Config { color: ColorChoice::Always, ..config };
+        let mut out = Vec::new();
+        search_contents("a\nfoo b", &config, "notes.txt", &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("\x1b[31mfoo\x1b[0m"));
+    }
+
+    /// Tests that scanned lines are counted, and that `-l` stops at the first match.
+    #[test]
+    fn test_lines_scanned() {
+        let contents = "a\nfoo\nb\nfoo\nc";
+        let config = Config { query: "foo".to_string(), ..Default::default() };
+        let stats = search_contents(contents, &config, "notes.txt", &mut io::sink()).unwrap();
+        assert_eq!(stats, SearchStats { matches: 2, lines_scanned: 5 });
+
+        let config = Config { files_with_matches: true, ..config };
+        let stats = search_contents(contents, &config, "notes.txt", &mut io::sink()).unwrap();
+        assert_eq!(stats, SearchStats { matches: 1, lines_scanned: 2 });
+    }
+
+    /// Tests that `--exclude-dir` prunes directories by name or glob.
+    #[test]
+    fn test_collect_files_exclude_dir() {
+        let root = env::temp_dir().join(format!("looneygrep-exclude-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/target")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join("src/lib.rs"), "foo").unwrap();
+        fs::write(root.join("src/target/out.rs"), "foo").unwrap();
+        fs::write(root.join(".git/HEAD"), "foo").unwrap();
+
+        let config = Config {
+            recursive: true,
+            exclude_dirs: vec!["targ*".to_string(), ".git".to_string()],
+            ..Default::default()
+        };
+        let mut files = Vec::new();
+        collect_files(&root, 1, &config, &mut HashSet::new(), &mut files).unwrap();
+        assert_eq!(files, vec![root.join("src/lib.rs")]);
+
+        assert!(glob_match("*.tmp", "a.tmp"));
+        assert!(glob_match("v?ndor", "vendor"));
+        assert!(!glob_match("target", "targets"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that hand-built configurations with conflicting options are rejected.
+    #[test]
+    fn test_validate() {
+        let config = Config { query: "foo".to_string(), file_path: "a.txt".to_string(), ..Default::default() };
+        assert_eq!(config.validate(), Ok(()));
+
+        let url = Some("https://example.com".to_string());
+        let conflicts = [
+            Config { query: String::new(), ..config.clone() },
+            Config { url: url.clone(), search_all: true, ..config.clone() },
+            Config { url: url.clone(), replace: true, ..config.clone() },
+            Config { file_path: String::new(), ..config.clone() },
+            Config { print0: true, ..config.clone() },
+            Config { regex: true, query: "(".to_string(), ..config.clone() },
+        ];
+        for conflict in conflicts {
+            assert!(matches!(conflict.validate(), Err(LooneygrepError::InvalidConfig(_))), "{:?}", conflict);
+        }
+        let config = Config { url, replace: true, output: Some("page.html".to_string()), file_path: String::new(), ..config };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    /// Tests that sources are collected in command-line order, with `-` as stdin.
+    #[test]
+    fn test_sources_order() {
+        let config = Config {
+            paths: vec!["a.txt".to_string(), "-".to_string(), "b.txt".to_string()],
+            url: Some("https://example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.sources().unwrap(),
+            vec![
+                Source::File(PathBuf::from("a.txt")),
+                Source::Stdin,
+                Source::File(PathBuf::from("b.txt")),
+                Source::Url("https://example.com".to_string()),
+            ]
+        );
+
+        let config = Config { file_path: "a.txt".to_string(), ..Default::default() };
+        assert_eq!(config.sources().unwrap(), vec![Source::File(PathBuf::from("a.txt"))]);
+    }
+
+    /// Tests that directory arguments are searched like `--all` rooted there.
+    #[test]
+    fn test_sources_directory_argument() {
+        let root = env::temp_dir().join(format!("looneygrep-dir-arg-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("a.txt"), "foo").unwrap();
+        fs::write(root.join("dir/b.txt"), "foo").unwrap();
+        fs::write(root.join("dir/sub/c.txt"), "foo").unwrap();
+
+        let paths = vec![root.join("a.txt").to_str().unwrap().to_string(), root.join("dir").to_str().unwrap().to_string()];
+        let config = Config { paths: paths.clone(), ..Default::default() };
+        assert_eq!(
+            config.sources().unwrap(),
+            vec![Source::File(root.join("a.txt")), Source::File(root.join("dir/b.txt"))]
+        );
+        let config = Config { paths, recursive: true, ..Default::default() };
+        assert_eq!(config.sources().unwrap().len(), 3);
 
-    /// Tests that context lines are correctly identified around matches.
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that the `./` added by walking the current directory is removed.
     #[test]
-    fn test_context_lines() {
-        let contents = "\
-line1
-match
-line3
-match
-line5";
-        let _config = Config {
-            /*config fields*/
-            query: "match".to_string(),
-            file_path: "".to_string(),
-            ignore_case: false,
-            replace: false,
-            url: None,
-            context: 1,
-            search_all: false,
+    fn test_strip_dot_prefix() {
+        assert_eq!(strip_dot_prefix(PathBuf::from("./src/lib.rs")), PathBuf::from("src/lib.rs"));
+        assert_eq!(strip_dot_prefix(PathBuf::from("src/lib.rs")), PathBuf::from("src/lib.rs"));
+        assert_eq!(strip_dot_prefix(PathBuf::from("../lib.rs")), PathBuf::from("../lib.rs"));
+    }
+
+    /// Tests that unexpanded glob arguments are expanded, and other paths kept as given.
+    #[test]
+    fn test_expand_glob() {
+        let root = env::temp_dir().join(format!("looneygrep-glob-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.rs"), "foo").unwrap();
+        fs::write(root.join("b.rs"), "foo").unwrap();
+        fs::write(root.join("c.txt"), "foo").unwrap();
+        let root_str = root.to_str().unwrap();
+
+        let expanded = expand_glob(&format!("{}/*.rs", root_str));
+        assert_eq!(expanded, vec![format!("{}/a.rs", root_str), format!("{}/b.rs", root_str)]);
+        assert_eq!(expand_glob("notes.txt"), vec!["notes.txt"]);
+        assert_eq!(expand_glob(&format!("{}/*.md", root_str)), vec![format!("{}/*.md", root_str)]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that `--absolute-path` labels files by their canonical path.
+    #[test]
+    fn test_absolute_path_label() {
+        let path = env::temp_dir().join(format!("looneygrep-absolute-{}.txt", std::process::id()));
+        fs::write(&path, "foo").unwrap();
+        let source = Source::File(path.clone());
+        let config = Config { absolute_path: true, ..Default::default() };
+        assert_eq!(source.label(&config), fs::canonicalize(&path).unwrap().to_string_lossy());
+        fs::remove_file(&path).unwrap();
+
+        let missing = Source::File(PathBuf::from("./missing.txt"));
+        assert_eq!(missing.label(&config), "./missing.txt");
+        assert_eq!(Source::Stdin.label(&config), STDIN_LABEL);
+    }
+
+    /// Tests that `--files-from` lists are read in order, skipping comments and blank lines.
+    #[test]
+    fn test_files_from() {
+        let list = env::temp_dir().join(format!("looneygrep-list-{}.txt", std::process::id()));
+        fs::write(&list, "# generated\nsrc/a.rs\r\n\n  \nsrc/b c.rs\n").unwrap();
+        let args = ["lg", "foo", "first.rs", "--files-from", list.to_str().unwrap()];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(
+            config.sources().unwrap(),
+            vec![
+                Source::File(PathBuf::from("first.rs")),
+                Source::File(PathBuf::from("src/a.rs")),
+                Source::File(PathBuf::from("src/b c.rs")),
+            ]
+        );
+        fs::remove_file(&list).unwrap();
+
+        let args = ["lg", "foo", "--files-from", "list.txt"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_ok());
+    }
+
+    /// Tests that every literal matcher variant agrees on matches and spans.
+    #[test]
+    fn test_matcher_variants() {
+        for ignore_case in [false, true] {
+            for matcher in [Matcher::new("Foo", ignore_case), Matcher::substring("Foo", ignore_case)] {
+                assert!(matcher.is_match("a Foo b"));
+                assert_eq!(matcher.is_match("a foo b"), ignore_case);
+                assert_eq!(matcher.find_all("Foo Foo"), vec![(0, 3), (4, 7)]);
+                assert!(matcher.find_all("bar").is_empty());
+            }
+        }
+        assert!(!Matcher::new("", false).is_match("anything"));
+    }
+
+    /// Tests that case-insensitive spans point into the original line when
+    /// lowercasing changes the byte length of a character.
+    #[test]
+    fn test_find_all_matches_multibyte() {
+        // 'ẞ' is 3 bytes but lowercases to the 2-byte 'ß'.
+        assert_eq!(find_all_matches("ẞfoo FOO", "foo", true), vec![(3, 6), (7, 10)]);
+        // 'İ' is 2 bytes but lowercases to 3 bytes ("i" plus a combining dot).
+        assert_eq!(find_all_matches("İx istanbul", "ISTANBUL", true), vec![(4, 12)]);
+        assert_eq!(find_all_matches("ẞfoo", "ß", true), vec![(0, 3)]);
+        let line = "ẞfoo";
+        let matcher = Matcher::new("FOO", true);
+        assert_eq!(replace_text(line, &matcher, "bar"), "ẞbar");
+        assert_eq!(
+            highlight_all_matches(line, &matcher, DEFAULT_MATCH_COLOR, usize::MAX),
+            "ẞ\x1b[31mfoo\x1b[0m"
+        );
+    }
+
+    /// Tests that sed-style substitution expressions are split correctly.
+    #[test]
+    fn test_parse_substitution() {
+        assert_eq!(
+            parse_substitution(r"s/foo(\d+)/bar$1/"),
+            Some((r"foo(\d+)".to_string(), "bar$1".to_string(), false))
+        );
+        assert_eq!(
+            parse_substitution(r"s|a\|b|c/d|gi"),
+            Some(("a|b".to_string(), "c/d".to_string(), true))
+        );
+        assert_eq!(parse_substitution("s/foo/bar"), None);
+        assert_eq!(parse_substitution("s/foo/bar/x"), None);
+        assert_eq!(parse_substitution("x/foo/bar/"), None);
+        assert_eq!(parse_substitution("s//bar/"), None);
+    }
+
+    /// Tests regex matching and capture-group replacement.
+    #[test]
+    fn test_regex_replace() {
+        let config = Config { query: r"foo(\d+)".to_string(), regex: true, ..Default::default() };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert!(matcher.is_match("x foo12 y"));
+        assert!(!matcher.is_match("foo"));
+        assert_eq!(matcher.find_all("foo1 foo22"), vec![(0, 4), (5, 10)]);
+        assert_eq!(replace_text("foo1 foo22", &matcher, "bar$1"), "bar1 bar22");
+
+        let args = ["lg", "notes.txt", "--replace-regex", r"s/foo(\d+)/bar$1/i"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.query, r"foo(\d+)");
+        assert_eq!(config.paths, vec!["notes.txt".to_string()]);
+        assert_eq!(config.replace_with.as_deref(), Some("bar$1"));
+        assert!(config.replace && config.regex && config.ignore_case);
+
+        let args = ["lg", "foo(", "notes.txt", "--regex"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--line-regexp` only matches lines equal to the query.
+    #[test]
+    fn test_line_regexp() {
+        let config = Config { query: "foo".to_string(), line_regexp: true, ..Default::default() };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert!(matcher.is_match("foo"));
+        assert!(matcher.is_match("foo\r"));
+        assert!(!matcher.is_match("foo bar"));
+        assert!(!matcher.is_match("FOO"));
+        assert_eq!(matcher.find_all("foo\r"), vec![(0, 3)]);
+        let config = Config { ignore_case: true, ..config };
+        assert!(Matcher::from_config(&config).unwrap().is_match("FOO"));
+    }
+
+    /// Tests that `--starts-with` and `--ends-with` anchor the query to the line.
+    #[test]
+    fn test_anchored_matches() {
+        let config = Config { query: "id".to_string(), starts_with: true, ..Default::default() };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert!(matcher.is_match("id,name"));
+        assert!(!matcher.is_match("uid,id"));
+        assert_eq!(matcher.find_all("id,id"), vec![(0, 2)]);
+        let config = Config { starts_with: false, ends_with: true, ignore_case: true, ..config };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert!(matcher.is_match("name,ID\r"));
+        assert!(!matcher.is_match("ID,name"));
+        assert_eq!(matcher.find_all("uid,Id\r\n"), vec![(4, 6)]);
+    }
+
+    /// Tests that non-UTF-8 text decodes and re-encodes losslessly.
+    #[test]
+    fn test_encoding_round_trip() {
+        let latin1 = Encoding::for_label(b"latin1").unwrap();
+        assert_eq!(decode(b"caf\xe9", latin1), "café");
+        assert_eq!(encode("café", latin1), b"caf\xe9");
+        let utf16 = encode("hé", UTF_16LE);
+        assert_eq!(utf16, [b'h', 0, 0xe9, 0]);
+        assert_eq!(decode(&utf16, UTF_16LE), "hé");
+
+        let args = ["lg", "foo", "poem.txt", "--encoding", "no-such-encoding"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that count mode returns the number of matching lines.
+    #[test]
+    fn test_count_matches() {
+        let contents = "foo\nbar\nfoo bar\n";
+        let config = Config {
+            query: "foo".to_string(),
+            count: true,
+            ..Default::default()
         };
-        let _output: Vec<(usize, &str)> = Vec::new();
-        // You'd need to refactor search_contents to write to output for testability
-        // For now, just check that context lines are correct
-        let lines: Vec<&str> = contents.lines().collect();
-        let matches: Vec<(usize, &str)> = lines.iter().enumerate()
-            .filter(|(_, line)| (*line).contains("match"))
-            .map(|(i, line)| (i, *line))
-            .collect();
-        assert_eq!(matches, vec![(1, "match"), (3, "match")]);
+        let (count, output) = render(contents, &config);
+        assert_eq!(count, 2);
+        assert_eq!(output, "2\n");
+    }
+
+    /// Tests that long lines are cut at a character boundary for display.
+    #[test]
+    fn test_truncate_columns() {
+        assert_eq!(truncate_columns("abcdef", None), ("abcdef", 0));
+        assert_eq!(truncate_columns("abcdef", Some(6)), ("abcdef", 0));
+        assert_eq!(truncate_columns("abcdef", Some(4)), ("abcd", 2));
+        assert_eq!(truncate_columns("ééééé", Some(2)), ("éé", 3));
+
+        let build = |value: &str| Config::build(["lg", "foo", "a.txt", "--max-columns", value].iter().map(|s| s.to_string()));
+        assert_eq!(build("80").unwrap().max_columns, Some(80));
+        for value in ["0", "eighty", "-5"] {
+            assert_eq!(build(value).unwrap_err(), "Invalid --max-columns value");
+        }
     }
 
     /// Tests that all matches in a line are replaced correctly.
     #[test]
     fn test_replace_all_matches() {
         let line = "foo bar foo";
-        let replaced = replace_all_matches(line, "foo", "baz", false);
+        let replaced = replace_text(line, &Matcher::new("foo", false), "baz");
         assert_eq!(replaced, "baz bar baz");
     }
 
+    /// Tests that the public `text::replace_all_matches` inserts `&` literally,
+    /// while the expanding variant substitutes the match.
+    #[test]
+    fn test_text_replace_all_matches_literal() {
+        assert_eq!(text::replace_all_matches("Tom and Jerry", "and", false, "&"), "Tom & Jerry");
+        assert_eq!(text::replace_all_matches("a=1", "1", false, "x&y=\\&"), "a=x&y=\\&");
+        assert_eq!(text::replace_all_matches_expanding("a=1", "1", false, "<&>"), "a=<1>");
+    }
+
+    /// Tests that `&` in the replacement stands for the matched text and `\&` for an ampersand.
+    #[test]
+    fn test_replace_ampersand() {
+        assert_eq!(replace_text("Foo foo", &Matcher::new("foo", true), "[&]"), "[Foo] [foo]");
+        assert_eq!(replace_text("foo", &Matcher::new("foo", false), "& \\& &"), "foo & foo");
+        let regex = Matcher::from_config(&Config { query: "f(o+)".to_string(), regex: true, ..Default::default() }).unwrap();
+        assert_eq!(replace_text("fooo", &regex, "<&:$1> \\&"), "<fooo:ooo> &");
+    }
+
+    /// Tests that a replacement containing the query is not rescanned.
+    #[test]
+    fn test_replacement_containing_query() {
+        assert_eq!(replace_text("aaaa", &Matcher::new("a", false), "aa"), "aaaaaaaa");
+        assert_eq!(replace_text("aAaA", &Matcher::new("a", true), "aa"), "aaaaaaaa");
+        assert_eq!(replace_text("Foo foo", &Matcher::new("foo", true), "foofoo"), "foofoo foofoo");
+    }
+
+    /// Tests that `--yes` replaces every match without prompting.
+    #[test]
+    fn test_replace_yes() {
+        let path = env::temp_dir().join(format!("looneygrep-yes-{}.txt", std::process::id()));
+        fs::write(&path, "foo bar\nbaz\nFoo foo").unwrap();
+        let args = ["lg", "foo", path.to_str().unwrap(), "--replace", "--replace-with", "qux", "--yes"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        run(config).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "qux bar\nbaz\nFoo qux");
+        fs::remove_file(&path).unwrap();
+
+        let args = ["lg", "foo", "poem.txt", "--replace", "--yes"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--pre` searches the output of the preprocessor command.
+    #[cfg(unix)]
+    #[test]
+    fn test_preprocessor() {
+        let path = env::temp_dir().join(format!("looneygrep-pre-{}.txt", std::process::id()));
+        fs::write(&path, "hello world").unwrap();
+        let config = Config { pre: Some("sed s/world/there/".to_string()), ..Default::default() };
+        assert_eq!(Source::File(path.clone()).read(&config).unwrap(), "hello there");
+        let config = Config { pre: Some("false".to_string()), ..Default::default() };
+        assert!(Source::File(path.clone()).read(&config).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let args = ["lg", "foo", "a.pdf", "--pre", "pdftotext", "--replace"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that text round-trips through an external editor command.
+    #[cfg(unix)]
+    #[test]
+    fn test_edit_in_editor() {
+        let edited = edit_in_editor("let foo = 1;\nfoo += 1;", "sed -i s/foo/bar/", "main.rs").unwrap();
+        assert_eq!(edited, "let bar = 1;\nbar += 1;\n");
+        assert!(edit_in_editor("foo", "false", "main.rs").is_err());
+
+        let args = ["lg", "foo", "main.rs", "--replace-interactive"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(config.replace && config.replace_interactive);
+    }
+
+    /// Tests that `--multiline` matches and replaces across line boundaries.
+    #[test]
+    fn test_multiline() {
+        let contents = "a\n/* one\ntwo */ b\nc /* three */\n";
+        let config = Config { query: r"/\*[^*]*\*/".to_string(), regex: true, multiline: true, ..Default::default() };
+        let (count, rendered) = render(contents, &config);
+        assert_eq!(count, 2);
+        assert!(rendered.starts_with("Preview of matches:\n2: /* one\n3: two */ b\n---\n4: c /* three */\n"));
+
+        let output = env::temp_dir().join(format!("looneygrep-multiline-{}.txt", std::process::id()));
+        let config = Config {
+            replace: true,
+            replace_with: Some("/**/".to_string()),
+            yes: true,
+            output: Some(output.to_str().unwrap().to_string()),
+            ..config
+        };
+        render(contents, &config);
+        assert_eq!(fs::read_to_string(&output).unwrap(), "a\n/**/ b\nc /**/\n");
+        fs::remove_file(&output).unwrap();
+
+        let args = ["lg", "^fn", "main.rs", "--multiline", "--count"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(render("fn a() {}\n  fn b() {}\nfn c() {}", &config).0, 2);
+    }
+
+    /// Tests that `--replace-case` applies to `--multiline` matches, and that
+    /// `--replace-whole-line` is rejected with `--multiline`.
+    #[test]
+    fn test_multiline_replace_options() {
+        let regex = Regex::new(r"(?m)one\ntwo").unwrap();
+        let config = Config { replace_case: Some(ReplaceCase::Upper), ..Default::default() };
+        let (text, replaced) = replace_multiline("a one\ntwo b", &regex, &config, usize::MAX);
+        assert_eq!(text, "a ONE\nTWO b");
+        assert_eq!(replaced, [(1, "one\ntwo".to_string(), "ONE\nTWO".to_string())]);
+
+        let args = ["lg", "one\ntwo", "a.txt", "--multiline", "--replace-whole-line", "--replace-with", "x"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `-l` and `-L` list files in `--multiline` mode, and that the
+    /// line-based output modes are rejected there.
+    #[test]
+    fn test_multiline_output_modes() {
+        let config = Config { query: r"a\nb".to_string(), regex: true, multiline: true, files_with_matches: true, ..Default::default() };
+        let mut out = Vec::new();
+        search_contents("a\nb", &config, "yes.txt", &mut out).unwrap();
+        search_contents("a b", &config, "no.txt", &mut out).unwrap();
+        let config = Config { files_with_matches: false, files_without_match: true, ..config };
+        search_contents("a\nb", &config, "yes.txt", &mut out).unwrap();
+        search_contents("a b", &config, "no.txt", &mut out).unwrap();
+        assert_eq!(out, b"yes.txt\nno.txt\n");
+
+        for flags in [&["--range", "4:4"][..], &["--json-lines"], &["--highlight-only"], &["--sort-matches"]] {
+            let args = ["lg", "a\nb", "a.txt", "--multiline"].iter().chain(flags);
+            assert!(Config::build(args.map(|s| s.to_string())).is_err(), "{:?}", flags);
+        }
+    }
+
+    /// Tests that `--dotall` lets `.` match newlines in a multiline search.
+    #[test]
+    fn test_dotall() {
+        let contents = "a\nstart x\ny end\nb start z end\n";
+        let args = ["lg", "start.*?end", "notes.txt", "--dotall"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(config.multiline && config.regex);
+        let (count, rendered) = render(contents, &config);
+        assert_eq!(count, 2);
+        assert!(rendered.starts_with("Preview of matches:\n2: start x\n3: y end\n---\n4: b start z end\n"));
+
+        let config = Config { dotall: false, ..config };
+        assert_eq!(render(contents, &config).0, 1);
+        let config = Config { dotall: true, multiline: false, ..config };
+        assert!(config.validate().is_err());
+    }
+
+    /// Tests that CRLF files are matched without the `\r` and keep their line endings on write.
+    #[test]
+    fn test_crlf_line_endings() {
+        assert_eq!(line_ending("a\r\nb\r\nc\n"), "\r\n");
+        assert_eq!(line_ending("a\nb\r\nc\n"), "\n");
+
+        let config = Config { query: "foo".to_string(), line_regexp: true, ..Default::default() };
+        let (count, rendered) = render("foo\r\nbar foo\r\nfoo\r", &config);
+        assert_eq!(count, 2);
+        assert!(!rendered.contains('\r'));
+
+        let path = env::temp_dir().join(format!("looneygrep-crlf-{}.txt", std::process::id()));
+        fs::write(&path, "foo one\r\ntwo\r\nfoo three\r\n").unwrap();
+        let args = ["lg", "foo", path.to_str().unwrap(), "--replace", "--replace-with", "bar", "--yes"];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bar one\r\ntwo\r\nbar three\r\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that the `--summary` table is sorted by count and aligned.
+    #[test]
+    fn test_write_summary() {
+        let mut per_source = vec![
+            ("b.rs".to_string(), 3),
+            ("src/lib.rs".to_string(), 40),
+            ("a.rs".to_string(), 3),
+        ];
+        let mut out = Vec::new();
+        write_summary(&mut out, "Matches by file:", &mut per_source).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\nMatches by file:\n40  src/lib.rs\n 3  a.rs\n 3  b.rs\n");
+    }
+
+    /// Tests that `--backup` and `--backup-dir` keep the original before replacing.
+    #[test]
+    fn test_backup() {
+        assert_eq!(backup_path("src/lib.rs", None), PathBuf::from("src/lib.rs.bak"));
+        assert_eq!(backup_path("./src/lib.rs", Some("bak")), PathBuf::from("bak/src/lib.rs"));
+        assert_eq!(backup_path("/etc/../hosts", Some("bak")), PathBuf::from("bak/etc/hosts"));
+
+        let root = env::temp_dir().join(format!("looneygrep-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("notes.txt");
+        fs::write(&path, "foo").unwrap();
+        let backups = root.join("backups");
+        let args = [
+            "lg", "foo", path.to_str().unwrap(), "--replace", "--replace-with", "bar", "--yes",
+            "--backup-dir", backups.to_str().unwrap(),
+        ];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bar");
+        assert_eq!(fs::read_to_string(backup_path(path.to_str().unwrap(), backups.to_str())).unwrap(), "foo");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that saved replacements are appended to the `--replace-log` file.
+    #[test]
+    fn test_replace_log() {
+        let root = env::temp_dir().join(format!("looneygrep-replace-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("notes.txt");
+        let log = root.join("replace.log");
+        fs::write(&path, "foo\nbar\nfoo foo").unwrap();
+        let args = [
+            "lg", "foo", path.to_str().unwrap(), "--replace", "--replace-with", "baz", "--yes",
+            "--replace-log", log.to_str().unwrap(),
+        ];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+
+        let log = fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].ends_with(&format!(" {}:1", path.display())));
+        assert_eq!(&lines[1..3], ["- foo", "+ baz"]);
+        assert!(lines[3].ends_with(&format!(" {}:3", path.display())));
+        assert_eq!(&lines[4..], ["- foo foo", "+ baz baz"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that `--replace-report` prints one escaped record per saved replacement.
+    #[test]
+    fn test_replace_report() {
+        assert_eq!(report_record("a:b.txt", 3, "x\\y", "1\n2"), "a\\:b.txt:3:x\\\\y:1\\n2");
+
+        let path = env::temp_dir().join(format!("looneygrep-replace-report-{}.txt", std::process::id()));
+        fs::write(&path, "foo\nbar\nfoo: foo\n").unwrap();
+        let args = [
+            "lg", "foo", path.to_str().unwrap(), "--replace", "--replace-with", "baz", "--yes", "--replace-report",
+        ];
+        let mut out = Vec::new();
+        run_with_writer(Config::build(args.iter().map(|s| s.to_string())).unwrap(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let records: Vec<&str> = out.lines().skip_while(|line| !line.starts_with("Replacements made")).skip(1).take(2).collect();
+        assert_eq!(
+            records,
+            [format!("{}:1:foo:baz", path.display()), format!("{}:3:foo\\: foo:baz\\: baz", path.display())]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that `--replace` refuses to overwrite a file that changed after it was read.
+    #[test]
+    fn test_replace_conflict() {
+        let path = env::temp_dir().join(format!("looneygrep-replace-conflict-{}.txt", std::process::id()));
+        fs::write(&path, "foo\nedited elsewhere\n").unwrap();
+        let file_path = path.to_str().unwrap();
+        let config = Config { query: "foo".to_string(), replace: true, yes: true, ..Default::default() };
+        // As if the file still held what was read before the other edit
+        let err = search_contents("foo\n", &config, file_path, &mut io::sink()).unwrap_err();
+        assert!(err.to_string().contains("changed on disk"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "foo\nedited elsewhere\n");
+
+        search_contents("foo\nedited elsewhere\n", &config, file_path, &mut io::sink()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "<REPLACED>\nedited elsewhere\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that `--replace-overview` lists the scope before replacing.
+    #[test]
+    fn test_replace_overview() {
+        let output = env::temp_dir().join(format!("looneygrep-overview-{}.txt", std::process::id()));
+        let config = Config {
+            query: "foo".to_string(),
+            replace: true,
+            replace_with: Some("bar".to_string()),
+            yes: true,
+            replace_overview: true,
+            output: Some(output.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let (_, rendered) = render("foo foo\nx\nfoo", &config);
+        assert!(rendered.contains("Found 3 matches in 2 lines; starting replacement...\nLines: 1, 3\n"));
+        fs::remove_file(&output).unwrap();
+    }
+
+    /// Tests that `--diff` prints a unified diff with merged hunks and leaves the file alone.
+    #[test]
+    fn test_replace_diff() {
+        let lines: Vec<String> = (1..=14).map(|n| format!("line {}", n)).collect();
+        let path = env::temp_dir().join(format!("looneygrep-diff-{}.txt", std::process::id()));
+        let contents = lines.join("\n").replace("line 2", "foo 2").replace("line 5", "foo 5").replace("line 13", "foo 13");
+        fs::write(&path, &contents).unwrap();
+        let file_path = path.to_str().unwrap();
+        let config = Config {
+            query: "foo".to_string(),
+            replace: true,
+            replace_with: Some("bar".to_string()),
+            diff: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        let stats = search_contents(&contents, &config, file_path, &mut out).unwrap();
+        assert_eq!(stats.matches, 3);
+        let expected = format!(
+            "--- a/{0}\n+++ b/{0}\n\
+             @@ -1,8 +1,8 @@\n line 1\n-foo 2\n+bar 2\n line 3\n line 4\n-foo 5\n+bar 5\n line 6\n line 7\n line 8\n\
+             @@ -10,5 +10,5 @@\n line 10\n line 11\n line 12\n-foo 13\n+bar 13\n line 14\n\\ No newline at end of file\n",
+            file_path
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that `--replace-whole-line` swaps out entire matching lines.
+    #[test]
+    fn test_replace_whole_line() {
+        let path = env::temp_dir().join(format!("looneygrep-whole-line-{}.txt", std::process::id()));
+        fs::write(&path, "debug = true\nname = x\nverbose_debug = 1").unwrap();
+        let args = [
+            "lg", "debug", path.to_str().unwrap(), "--replace-whole-line", "--replace-with", "debug = false", "--yes",
+        ];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "debug = false\nname = x\ndebug = false");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that whole-word mode leaves matches inside other words alone when replacing.
+    #[test]
+    fn test_word_regexp_replace() {
+        let config = Config { query: "id".to_string(), word_regexp: true, ..Default::default() };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert!(!matcher.is_match("width = 3"));
+        assert!(matcher.is_match("width = id"));
+        assert_eq!(
+            replace_all_matches("let id = width + id_x + (id);", &matcher, "identifier", None),
+            ("let identifier = width + id_x + (identifier);".to_string(), 2)
+        );
+
+        let config = Config { regex: true, query: "i(d)".to_string(), ..config };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert_eq!(replace_all_matches("id width", &matcher, "$1", None), ("d width".to_string(), 1));
+    }
+
+    /// Tests that whole-word mode treats non-ASCII letters and combining marks as
+    /// part of words, with and without `--regex`.
+    #[test]
+    fn test_word_regexp_unicode() {
+        for regex in [false, true] {
+            let matcher = |query: &str| {
+                let config = Config { query: query.to_string(), word_regexp: true, regex, ..Default::default() };
+                Matcher::from_config(&config).unwrap()
+            };
+            assert_eq!(matcher("café").find_all("un café noir"), vec![(3, 8)]);
+            assert!(!matcher("café").is_match("les cafés"));
+            assert!(!matcher("caf").is_match("un café noir"));
+            assert!(matcher("naïve").is_match("«naïve»"));
+            assert!(!matcher("λόγος").is_match("διάλόγος"));
+            assert!(matcher("東京").is_match("in 東京 today"));
+            assert!(!matcher("東京").is_match("東京都"));
+            // "cafe" followed by a combining acute accent is "café", not "cafe"
+            assert!(!matcher("cafe").is_match("un cafe\u{301} noir"), "regex: {}", regex);
+            // So are other scripts' marks: a Devanagari virama and a Thai vowel sign
+            assert!(!matcher("क").is_match("क\u{94D}ष"), "regex: {}", regex);
+            assert!(!matcher("ก").is_match("ก\u{E34}น"), "regex: {}", regex);
+            assert!(matcher("क\u{94D}ष").is_match("a क\u{94D}ष b"), "regex: {}", regex);
+        }
+    }
+
+    /// Tests that `--preserve-case` gives each replacement the case of its match.
+    #[test]
+    fn test_preserve_case() {
+        assert_eq!(match_case("colour", "COLOR"), "COLOUR");
+        assert_eq!(match_case("colour", "Color"), "Colour");
+        assert_eq!(match_case("Colour", "color"), "colour");
+        assert_eq!(match_case("colour", "cOLoR"), "colour");
+        assert_eq!(match_case("x", "123"), "x");
+        assert_eq!(match_case("ü", "É"), "Ü");
+
+        let args = ["lg", "color", "a.css", "--ignore-case", "--replace-with", "colour", "--preserve-case"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(config.replace);
+        let matcher = Matcher::from_config(&config).unwrap();
+        let line = "color: Color COLOR coLor";
+        assert_eq!(replace_line(line, &matcher, &config, None).0, "colour: Colour COLOUR colour");
+
+        let config = Config { regex: true, query: "(c)olor".to_string(), replace_with: Some("${1}olour".to_string()), ..config };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert_eq!(replace_line("Color", &matcher, &config, None).0, "Colour");
+        assert_eq!(replace_chosen("COLOR color", &[(0, 5)], &matcher, &config), "COLOUR color");
+
+        let args = ["lg", "color", "a.css", "--preserve-case"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--replace-case` rewrites each match in the chosen case.
+    #[test]
+    fn test_replace_case() {
+        assert_eq!(ReplaceCase::Title.apply("hELLO wORLD-wide"), "Hello World-Wide");
+        let matcher = Matcher::new("todo", true);
+        assert_eq!(
+            replace_matches_with("todo: Todo", &matcher, None, |text| ReplaceCase::Upper.apply(text)),
+            ("TODO: TODO".to_string(), 2)
+        );
+
+        let args = ["lg", "todo", "notes.txt", "--replace-case", "upper", "--yes"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.replace_case, Some(ReplaceCase::Upper));
+        assert!(config.replace);
+        let args = ["lg", "todo", "notes.txt", "--replace-case", "camel"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--replace-if-preceded-by` and `--replace-if-followed-by` only
+    /// replace matches with that text next to them.
+    #[test]
+    fn test_replace_conditions() {
+        let config = Config {
+            query: "foo".to_string(),
+            replace_if_followed_by: Some("bar".to_string()),
+            ..Default::default()
+        };
+        let matcher = config.replace_matcher(&Matcher::from_config(&config).unwrap());
+        assert_eq!(replace_all_matches("foobar foo foobar", &matcher, "x", None), ("xbar foo xbar".to_string(), 2));
+        assert!(!matcher.is_match("foo barfoo"));
+
+        let config = Config {
+            query: "(\\w+)=1".to_string(),
+            regex: true,
+            replace_if_preceded_by: Some("set ".to_string()),
+            ..Default::default()
+        };
+        let matcher = config.replace_matcher(&Matcher::from_config(&config).unwrap());
+        assert_eq!(
+            replace_all_matches("set a=1, b=1, set c=1", &matcher, "$1=0", Some(1)),
+            ("set a=0, b=1, set c=1".to_string(), 1)
+        );
+
+        let path = env::temp_dir().join(format!("looneygrep-conditions-{}.txt", std::process::id()));
+        fs::write(&path, "foobar\nfoo\nfoo foobar").unwrap();
+        let args = [
+            "lg", "foo", path.to_str().unwrap(), "--replace-if-followed-by", "bar", "--replace-with", "baz", "--yes",
+        ];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bazbar\nfoo\nfoo bazbar");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that picking occurrences replaces only those spans of a line.
+    #[test]
+    fn test_replace_chosen() {
+        let config = Config { query: "foo".to_string(), replace_with: Some("[&]".to_string()), ..Default::default() };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert_eq!(replace_chosen("foo foo foo", &[(4, 7)], &matcher, &config), "foo [foo] foo");
+
+        let config = Config {
+            query: "f(o+)".to_string(),
+            regex: true,
+            replace_with: Some("g$1".to_string()),
+            ..Default::default()
+        };
+        let matcher = Matcher::from_config(&config).unwrap();
+        assert_eq!(replace_chosen("fo foo fooo", &[(0, 2), (7, 11)], &matcher, &config), "go foo gooo");
+        let config = Config { replace_case: Some(ReplaceCase::Upper), ..config };
+        assert_eq!(replace_chosen("fo foo", &[(3, 6)], &matcher, &config), "fo FOO");
+    }
+
+    /// Tests that `--insert-before` and `--insert-after` wrap matches, literally, without removing them.
+    #[test]
+    fn test_insert_around_matches() {
+        let path = env::temp_dir().join(format!("looneygrep-insert-{}.txt", std::process::id()));
+        fs::write(&path, "let id = id + 1;").unwrap();
+        let args = [
+            "lg", "id", path.to_str().unwrap(), "-w", "--insert-before", "self.", "--insert-after", "&$1", "--yes",
+        ];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "let self.id&$1 = self.id&$1 + 1;");
+        let args = ["lg", "id", path.to_str().unwrap(), "--insert-after", "x", "--replace-with", "y"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that `--replace-count` stops replacing after N matches, within and across lines.
+    #[test]
+    fn test_replace_count() {
+        assert_eq!(replace_all_matches("foo foo foo", &Matcher::new("foo", false), "x", Some(2)), ("x x foo".to_string(), 2));
+        let regex = Matcher::from_config(&Config { query: "f(o+)".to_string(), regex: true, ..Default::default() }).unwrap();
+        assert_eq!(replace_all_matches("foo fo", &regex, "g$1", Some(1)), ("goo fo".to_string(), 1));
+
+        let path = env::temp_dir().join(format!("looneygrep-replace-count-{}.txt", std::process::id()));
+        fs::write(&path, "foo foo\nfoo\nfoo").unwrap();
+        let args = [
+            "lg", "foo", path.to_str().unwrap(), "--replace", "--replace-with", "bar", "--yes",
+            "--replace-count", "3",
+        ];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bar bar\nbar\nfoo");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that unreadable files are skipped, reported in the result, and
+    /// don't stop the other files from being searched.
+    #[test]
+    fn test_unreadable_sources() {
+        let path = env::temp_dir().join(format!("looneygrep-readable-{}.txt", std::process::id()));
+        let output = env::temp_dir().join(format!("looneygrep-readable-out-{}.txt", std::process::id()));
+        fs::write(&path, "foo").unwrap();
+        let config = Config {
+            query: "foo".to_string(),
+            paths: vec!["/nonexistent/looneygrep.txt".to_string(), path.to_str().unwrap().to_string()],
+            count: true,
+            no_messages: true,
+            output_file: Some(output.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let err = run(config).unwrap_err();
+        assert_eq!(err.downcast_ref::<LooneygrepError>(), Some(&LooneygrepError::UnreadableSources(1)));
+        assert!(fs::read_to_string(&output).unwrap().ends_with("===\n1\n"));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&output).unwrap();
+    }
+
+    /// Tests that `run_with_writer` sends the whole run to the given sink and
+    /// returns the match count across all sources.
+    #[test]
+    fn test_run_with_writer() {
+        let first = env::temp_dir().join(format!("looneygrep-writer-a-{}.txt", std::process::id()));
+        let second = env::temp_dir().join(format!("looneygrep-writer-b-{}.txt", std::process::id()));
+        fs::write(&first, "foo\nbar").unwrap();
+        fs::write(&second, "foo foo\nfoo").unwrap();
+        let config = Config {
+            query: "foo".to_string(),
+            paths: vec![first.to_str().unwrap().to_string(), second.to_str().unwrap().to_string()],
+            count: true,
+            total: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        assert_eq!(run_with_writer(config.clone(), &mut out).unwrap(), 3);
+        let rendered = String::from_utf8(out).unwrap();
+        assert!(rendered.contains(&format!("=== Searching in file: {} ===\n1\n", first.display())));
+        assert!(rendered.ends_with("\nTotal: 3\n"));
+
+        // The header notes when the file name itself matches
+        let mut out = Vec::new();
+        run_with_writer(Config { query: "writer-b".to_string(), ..config.clone() }, &mut out).unwrap();
+        let rendered = String::from_utf8(out).unwrap();
+        assert!(rendered.contains(&format!("=== Searching in file: {} ===", first.display())));
+        assert!(rendered.contains(&format!("=== Searching in file: {} (filename matches query) ===", second.display())));
+
+        // Headers are colored only when colors are on
+        let mut out = Vec::new();
+        run_with_writer(Config { color: ColorChoice::Always, ..config }, &mut out).unwrap();
+        let header = format!("\x1b[1;36m=== Searching in file: {} ===\x1b[0m\n", first.display());
+        assert!(String::from_utf8(out).unwrap().contains(&header));
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+    }
+
+    /// Tests that `--no-filename` drops the headers even when several files are searched.
+    #[test]
+    fn test_no_filename() {
+        let first = env::temp_dir().join(format!("looneygrep-no-filename-a-{}.txt", std::process::id()));
+        let second = env::temp_dir().join(format!("looneygrep-no-filename-b-{}.txt", std::process::id()));
+        fs::write(&first, "foo").unwrap();
+        fs::write(&second, "foo").unwrap();
+        let args = ["lg", "foo", first.to_str().unwrap(), second.to_str().unwrap(), "--count", "-h"];
+        let mut out = Vec::new();
+        run_with_writer(Config::build(args.iter().map(|s| s.to_string())).unwrap(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "1\n1\n");
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+    }
+
+    /// Tests that replacing across a directory skips binary and generated files
+    /// unless `--force` is given.
+    #[test]
+    fn test_bulk_replace_skips_generated() {
+        let root = env::temp_dir().join(format!("looneygrep-generated-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("app.js"), "foo").unwrap();
+        fs::write(root.join("other.bin"), "\0").unwrap();
+        fs::write(root.join("app.min.js"), "foo").unwrap();
+        fs::write(root.join("data.bin"), "foo\0").unwrap();
+        fs::write(root.join("raw.bin"), b"foo\0\xff\xfe").unwrap();
+        fs::write(root.join("bundle.js"), format!("foo{}", " ".repeat(GENERATED_LINE_LENGTH))).unwrap();
+        let dir = root.to_str().unwrap();
+        // A glob naming the directory counts as a directory too
+        let pattern = format!("{}*", dir);
+        let args = ["lg", "foo", &pattern, "--replace", "--replace-with", "bar", "--yes", "--color", "never"];
+        let mut out = Vec::new();
+        run_with_writer(Config::build(args.iter().map(|s| s.to_string())).unwrap(), &mut out).unwrap();
+        assert_eq!(fs::read_to_string(root.join("app.js")).unwrap(), "bar");
+        assert_eq!(fs::read_to_string(root.join("app.min.js")).unwrap(), "foo");
+        assert_eq!(fs::read_to_string(root.join("data.bin")).unwrap(), "foo\0");
+        assert_eq!(fs::read(root.join("raw.bin")).unwrap(), b"foo\0\xff\xfe");
+        assert!(fs::read_to_string(root.join("bundle.js")).unwrap().starts_with("foo"));
+        let rendered = String::from_utf8(out).unwrap();
+        assert!(rendered.contains("data.bin looks binary"));
+        assert!(rendered.contains("data.bin (binary, will be skipped)"));
+        assert!(rendered.contains("raw.bin (binary, will be skipped)"));
+        assert!(rendered.contains("5 matching lines in 5 files."));
+        assert!(!rendered.contains("other.bin looks"));
+
+        // Forced, a binary that isn't valid UTF-8 can't be rewritten safely, so it fails to read
+        let args = ["lg", "foo", dir, "--replace", "--replace-with", "bar", "--yes", "--force"];
+        let result = run_with_writer(Config::build(args.iter().map(|s| s.to_string())).unwrap(), &mut Vec::new());
+        assert_eq!(result, Err(LooneygrepError::UnreadableSources(1)));
+        assert_eq!(fs::read(root.join("raw.bin")).unwrap(), b"foo\0\xff\xfe");
+        assert_eq!(fs::read_to_string(root.join("app.min.js")).unwrap(), "bar");
+        assert_eq!(fs::read_to_string(root.join("data.bin")).unwrap(), "bar\0");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that `--name-only` matches file paths and ignores contents.
+    #[test]
+    fn test_name_only() {
+        let root = env::temp_dir().join(format!("looneygrep-name-only-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("notes.txt"), "config").unwrap();
+        fs::write(root.join("config.toml"), "").unwrap();
+        let args = ["lg", "config", root.to_str().unwrap(), "--name-only"];
+        let mut out = Vec::new();
+        let matches = run_with_writer(Config::build(args.iter().map(|s| s.to_string())).unwrap(), &mut out).unwrap();
+        assert_eq!(matches, 1);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", root.join("config.toml").display()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tests that `--output` leaves the original file untouched.
+    #[test]
+    fn test_replace_output() {
+        let path = env::temp_dir().join(format!("looneygrep-in-{}.txt", std::process::id()));
+        let output = env::temp_dir().join(format!("looneygrep-out-{}.txt", std::process::id()));
+        fs::write(&path, "foo bar").unwrap();
+        let args = [
+            "lg", "foo", path.to_str().unwrap(), "--replace", "--replace-with", "baz", "--yes",
+            "--output", output.to_str().unwrap(),
+        ];
+        run(Config::build(args.iter().map(|s| s.to_string())).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "foo bar");
+        assert_eq!(fs::read_to_string(&output).unwrap(), "baz bar");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        let args = ["lg", "foo", "--all", "--output", "out.txt"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+        let args = ["lg", "foo", "a.txt", "--url", "http://example.com", "--output", "out.txt"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that replacing in a downloaded page saves the result to `--output`.
+    #[test]
+    fn test_replace_url_output() {
+        let output = env::temp_dir().join(format!("looneygrep-url-out-{}.html", std::process::id()));
+        let config = Config {
+            query: "foo".to_string(),
+            url: Some("http://example.com".to_string()),
+            replace: true,
+            replace_with: Some("bar".to_string()),
+            yes: true,
+            ..Default::default()
+        };
+        let (_, rendered) = render("<p>foo</p>", &config);
+        assert!(rendered.contains("needs --output"));
+
+        let config = Config { output: Some(output.to_str().unwrap().to_string()), ..config };
+        render("<p>foo</p>", &config);
+        assert_eq!(fs::read_to_string(&output).unwrap(), "<p>bar</p>");
+        fs::remove_file(&output).unwrap();
+    }
+
+    /// Tests that an empty query is rejected and never replaces anything.
+    #[test]
+    fn test_empty_query() {
+        let args = ["lg", "", "poem.txt"].iter().map(|s| s.to_string());
+        assert!(Config::build(args).is_err());
+        assert_eq!(replace_text("foo", &Matcher::new("", false), "bar"), "foo");
+        assert_eq!(replace_text("foo", &Matcher::new("", true), "bar"), "foo");
+    }
+
     /// Tests that all matches in a line are highlighted with ANSI codes.
     #[test]
     fn test_highlight_all_matches() {
         let line = "foo bar foo";
-        let highlighted = highlight_all_matches(line, "foo", false);
+        let highlighted = highlight_all_matches(line, &Matcher::new("foo", false), DEFAULT_MATCH_COLOR, usize::MAX);
         assert!(highlighted.contains("\x1b[31mfoo\x1b[0m"));
+
+        // Past the cap, the rest of the line is still shown, just not highlighted
+        let highlighted = highlight_all_matches("aaaa", &Matcher::new("a", false), DEFAULT_MATCH_COLOR, 2);
+        assert_eq!(highlighted, "\x1b[31ma\x1b[0m\x1b[31ma\x1b[0maa");
+
+        let args = ["lg", "foo", "notes.txt", "--highlight-first", "--color", "always"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        let mut out = Vec::new();
+        search_contents("foo foo foo", &config, "", &mut out).unwrap();
+        let rendered = String::from_utf8(out).unwrap();
+        assert!(rendered.contains(": \x1b[31mfoo\x1b[0m"), "{:?}", rendered);
+        assert_eq!(rendered.matches("\x1b[31m").count(), 1);
+    }
+
+    /// Tests that line numbers are colored and that `--color` is parsed.
+    #[test]
+    fn test_line_number_color() {
+        let config = Config { query: "foo".to_string(), file_path: "notes.txt".to_string(), ..Default::default() };
+        let mut out = Vec::new();
+        search_contents("bar\nfoo\n", &config, "notes.txt", &mut out).unwrap();
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.contains("\x1b[32m2\x1b[0m: "));
+
+        let args = ["lg", "foo", "notes.txt", "--color", "never"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.color, ColorChoice::Never);
+        assert!(!config.use_color());
+        let args = ["lg", "foo", "notes.txt", "--color", "sometimes"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `--match-color` values map to the expected ANSI escapes.
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("green").as_deref(), Some("\x1b[32m"));
+        assert_eq!(parse_color("CYAN").as_deref(), Some("\x1b[36m"));
+        assert_eq!(parse_color("208").as_deref(), Some("\x1b[38;5;208m"));
+        assert_eq!(parse_color("#ff8000").as_deref(), Some("\x1b[38;2;255;128;0m"));
+        assert_eq!(parse_color("256"), None);
+        assert_eq!(parse_color("#fff"), None);
+        assert_eq!(parse_color("chartreuse"), None);
+    }
+
+    /// Tests that `--highlight-style` turns the match color into a background or reverse video.
+    #[test]
+    fn test_highlight_style() {
+        let args = ["lg", "foo", "notes.txt", "--match-color", "#ff8000", "--highlight-style", "bg"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.match_color(), "\x1b[48;2;255;128;0m");
+        let config = Config { match_color: None, ..config };
+        assert_eq!(config.match_color(), "\x1b[41m");
+        let config = Config { highlight_style: HighlightStyle::Reverse, ..config };
+        assert_eq!(config.match_color(), "\x1b[7m");
+        let args = ["lg", "foo", "notes.txt", "--highlight-style", "underline"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    /// Tests that `~` and environment variables in paths are expanded.
+    #[test]
+    fn test_expand_path() {
+        let home = dirs::home_dir().unwrap().to_string_lossy().to_string();
+        assert_eq!(expand_path("~/notes.txt"), format!("{}/notes.txt", home));
+        assert_eq!(expand_path("~"), home);
+        assert_eq!(expand_path("~other/notes.txt"), "~other/notes.txt");
+        let var = env::var("PATH").unwrap();
+        assert_eq!(expand_path("$PATH/x"), format!("{}/x", var));
+        assert_eq!(expand_path("a${PATH}b"), format!("a{}b", var));
+        assert_eq!(expand_path("$LOONEYGREP_UNSET_VAR/x"), "$LOONEYGREP_UNSET_VAR/x");
+        assert_eq!(expand_path("${LOONEYGREP_UNSET_VAR}/${oops"), "${LOONEYGREP_UNSET_VAR}/${oops");
+        assert_eq!(expand_path("cost$/x"), "cost$/x");
+    }
+
+    /// Tests that atomic writes replace the file, leave no temp file behind and
+    /// refuse read-only files.
+    #[test]
+    fn test_write_atomic() {
+        let dir = env::temp_dir().join(format!("looneygrep-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        fs::write(&path, "old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+        assert!(write_atomic(&path, b"newer").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that `--help` and `--version` are recognized wherever a flag can
+    /// appear and skip validation, but not as an option's value or after `--`.
+    #[test]
+    fn test_help_and_version() {
+        let build = |args: &[&str]| Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(build(&["lg", "--help"]).help);
+        let config = build(&["lg", "foo", "--replace", "--version"]);
+        assert!(config.version && !config.help);
+        assert!(!build(&["lg", "foo", "notes.txt"]).help);
+
+        let config = build(&["lg", "foo", "notes.txt", "--replace-with", "--help"]);
+        assert!(!config.help);
+        assert_eq!(config.replace_with.as_deref(), Some("--help"));
+        assert!(!build(&["lg", "foo", "f", "--replace-with", "--version"]).version);
+        let config = build(&["lg", "--help", "notes.txt"]);
+        assert!(!config.help);
+        assert_eq!((config.query.as_str(), config.paths.as_slice()), ("--help", &["notes.txt".to_string()][..]));
+        let config = build(&["lg", "foo", "--", "--help", "--version"]);
+        assert!(!config.help && !config.version);
+        assert_eq!(config.paths, ["--help", "--version"]);
+        assert!(USAGE.starts_with("Usage: looneygrep"));
+    }
+
+    /// Tests that `--header` values are split into a valid name and value.
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("Authorization: Bearer abc"),
+            Some(("Authorization".to_string(), "Bearer abc".to_string()))
+        );
+        assert_eq!(parse_header("X-Empty:"), Some(("X-Empty".to_string(), String::new())));
+        assert_eq!(parse_header("no colon"), None);
+        assert_eq!(parse_header("Bad Name: value"), None);
+        assert_eq!(parse_header("X-Bad: line\nbreak"), None);
+    }
+
+    /// Tests that ANSI escapes are stripped, even when split across writes.
+    #[test]
+    fn test_ansi_stripper() {
+        let mut out = AnsiStripper::new(Vec::new());
+        out.write_all(b"a\x1b[31mred\x1b[0m b\x1b[38;2;1").unwrap();
+        out.write_all(b";2;3mc\x1b").unwrap();
+        out.write_all(b"[0m").unwrap();
+        assert_eq!(out.inner, b"ared bc");
+
+        // ESC ESC starts a fresh sequence instead of leaking the second one
+        let mut out = AnsiStripper::new(Vec::new());
+        out.write_all(b"x\x1b\x1b[38;2;1;2;3m\x1b[31mfoo").unwrap();
+        assert_eq!(out.inner, b"xfoo");
+    }
+
+    /// Tests that matches on a syntax-highlighted line are drawn over syntect's
+    /// colors rather than inside them, so stripping leaves the plain line.
+    #[test]
+    fn test_highlight_code_line() {
+        let line = "let foo = \"foo\";";
+        let spans = Matcher::new("foo", false).find_all(line);
+        let highlighted = highlight_line(line, &spans, DEFAULT_MATCH_COLOR, "main.rs", &[]);
+        assert_eq!(highlighted.matches("\x1b[31mfoo\x1b[0m").count(), 2);
+        let mut out = AnsiStripper::new(Vec::new());
+        out.write_all(highlighted.as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(out.inner).unwrap(), line);
+
+        for color in [ColorChoice::Always, ColorChoice::Never] {
+            let config = Config { query: "foo".to_string(), color, ..Default::default() };
+            let (_, rendered) = render("const foo = 1;\nlet x = foo(foo);", &Config { file_path: "app.js".to_string(), ..config });
+            assert_eq!(rendered, "Preview of matches:\n1: const foo = 1;\n2: let x = foo(foo);\n(JavaScript source file detected)\n");
+        }
+        // Without color, neither syntect nor match escapes are added in the first place
+        let config = Config { query: "foo".to_string(), color: ColorChoice::Never, ..Default::default() };
+        let mut out = Vec::new();
+        search_contents("let foo = 1;", &config, "main.rs", &mut out).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("38;2;"));
     }
 
     /// Tests that file type notes print for various extensions.
     #[test]
     fn test_file_type_note() {
-        // This just checks that the function runs; for real tests, capture stdout
-        print_file_type_note("test.rs");
-        print_file_type_note("test.py");
-        print_file_type_note("test.txt");
+        let mut out = Vec::new();
+        print_file_type_note("test.rs", &mut out).unwrap();
+        print_file_type_note("test.py", &mut out).unwrap();
+        print_file_type_note("test.txt", &mut out).unwrap();
+        print_file_type_note("test.unknown", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "(Rust source file detected)\n(Python source file detected)\n(Text file detected)\n"
+        );
     }
 
     /// Tests that syntax highlighting adds ANSI codes for supported file types.
     #[test]
     fn test_syntax_highlight_line() {
         let line = "fn main() {}";
-        let highlighted = syntax_highlight_line(line, "test.rs");
+        let highlighted = highlight_line(line, &[], "", "test.rs", &[]);
         assert!(highlighted.contains("\x1b["));
     }
+
+    /// Tests that `--map-ext` highlights an unknown extension as the one it maps to.
+    #[test]
+    fn test_map_ext() {
+        let line = "fn main() {}";
+        let plain = highlight_line(line, &[], "", "test.rsx", &[]);
+        let args = ["lg", "foo", "notes.txt", "--map-ext", ".rsx=rs"];
+        let config = Config::build(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.ext_map, vec![("rsx".to_string(), "rs".to_string())]);
+        let mapped = highlight_line(line, &[], "", "test.rsx", &config.ext_map);
+        assert_eq!(mapped, highlight_line(line, &[], "", "test.rs", &[]));
+        assert_ne!(mapped, plain);
+        let args = ["lg", "foo", "notes.txt", "--map-ext", "rsx"];
+        assert!(Config::build(args.iter().map(|s| s.to_string())).is_err());
+    }
 }
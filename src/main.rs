@@ -26,7 +26,7 @@
 use std::env;
 use std::process;
 
-use looneygrep::Config;
+use looneygrep::{Config, LooneygrepError, OutputFormat};
 
 /// The main entry point for the Looneygrep application.
 ///
@@ -38,9 +38,44 @@ fn main() {
             eprintln!("Problem parsing arguments: {}", err);
             process::exit(1);
         });
-    if let Err(e) = looneygrep::run(config) {
-        eprintln!("Application error: {}", e);
+    if config.help {
+        print!("{}", looneygrep::USAGE);
+        return;
+    }
+    if config.version {
+        println!("looneygrep {}", looneygrep::VERSION);
+        return;
+    }
+    if let Err(e) = looneygrep::install_interrupt_handler() {
+        eprintln!("Warning: could not install Ctrl-C handler: {}", e);
+    }
+    // File lists, JSON, diffs, NUL-separated and filtered output are meant for other programs,
+    // so they get no trailing message
+    let list_only = config.files_with_matches
+        || config.files_without_match
+        || config.is_stdin_filter()
+        || config.diff
+        || config.name_only
+        || config.null
+        || config.output_format == OutputFormat::JsonLines;
+    let no_messages = config.no_messages;
+    let count = config.count;
+    let matches = looneygrep::run(config).unwrap_or_else(|e| {
+        // With --no-messages, unreadable files only show up in the exit code
+        let unreadable = matches!(e.downcast_ref(), Some(LooneygrepError::UnreadableSources(_)));
+        if !(no_messages && unreadable) {
+            eprintln!("Application error: {}", e);
+        }
         process::exit(1);
+    });
+    if looneygrep::interrupted() {
+        process::exit(130);
+    }
+    // Like grep, --count exits with 1 when nothing matched, so it can be used in conditionals
+    if count && matches == 0 {
+        process::exit(1);
+    }
+    if !list_only {
+        println!("Search completed successfully.");
     }
-    println!("Search completed successfully.");
 }
\ No newline at end of file